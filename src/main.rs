@@ -1,41 +1,264 @@
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fmt;
 use std::fs;
 use std::fs::File;
-use std::io::Write;
+use std::io::{self, IsTerminal, Read, Write};
+use std::panic;
+use std::path::Path;
+use std::process::{self, Command, Stdio};
+use std::sync::Once;
+use std::time::{Duration, Instant};
 
 #[derive(Debug, PartialEq, Clone)]
 enum Token {
     Identifier(String),
-    Number(f64),
+    Number(i64),
+    StringLiteral(String),
     Plus,
     Minus,
     Multiply,
     Divide,
+    Modulo,
     Assign,
     Print,
     If,
     Else,
     While,
+    Do,
     For,
     In,
     Break,
     Continue,
+    Match,
     LParen,
     RParen,
     LBrace,
     RBrace,
     Comma,
+    Colon,
     LessThan,
     GreaterThan,
     LessEqual,
     GreaterEqual,
     NotEqual,
     Equal,
+    And,
+    Or,
+    Not,
+    // Trivia, produced only with LexerOptions::preserve_trivia
+    Whitespace(String),
+    Comment(String),
     EOF,
 }
 
+impl Token {
+    fn is_trivia(&self) -> bool {
+        matches!(self, Token::Whitespace(_) | Token::Comment(_))
+    }
+}
+
+// Location of a token in the source: character offsets plus the 1-based line/column of its start
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Span {
+    start: usize,
+    end: usize,
+    line: usize,
+    column: usize,
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+// Where a variable in the AST was written, for tools such as build_symbol_index. Nodes the
+// compiler makes up have none. It never makes two nodes differ: `x` read on two lines is the same
+// expression to the optimizer.
+#[derive(Debug, Clone, Copy, Default)]
+struct Origin(Option<Span>);
+
+impl PartialEq for Origin {
+    fn eq(&self, _: &Origin) -> bool {
+        true
+    }
+}
+
+// Compile errors. Each has the code --explain describes, shown before the message. The lexer and
+// parser stop at the first error with `fail`; most variants carry the message, which gives the
+// position.
+#[derive(Debug, Clone, PartialEq)]
+enum CompileError {
+    UnterminatedComment(String),
+    InvalidNumber(String),
+    UnexpectedCharacter(String),
+    UnexpectedToken(String),
+    // A `=` where `==` was meant
+    MisspelledComparison(String),
+    UnknownFunction(String),
+    WrongArgumentCount(String),
+    DuplicateMatchArm(String),
+    // break/continue outside a loop, or a loop label that is unknown or shadows another
+    MisplacedLoopControl(String),
+    // A variable declared both `global` and `local`
+    ConflictingStorage(String),
+    // The generated code exceeds the instruction limit (--max-instructions)
+    ProgramTooLarge { limit: usize },
+    // Under --strict-bool
+    TypeMismatch(String),
+    InvalidMultipleAssignment(String),
+    InvalidString(String),
+    InvalidPrintf(String),
+    InvalidMacro(String),
+    MisplacedDefer(String),
+    // A static_assert condition that folds to zero, or that doesn't fold to a constant
+    StaticAssertFailed(String),
+    StaticAssertNotConstant(String),
+    InvalidPrintWidth(String),
+    InvalidAssignmentTarget(String),
+    // `#if`/`#else`/`#endif` that don't pair up, or an `#if` without a single name
+    UnbalancedDirective(String),
+    // The source stops being UTF-8 at byte `offset`, on line `line`
+    InvalidUtf8 { line: usize, offset: usize },
+}
+
+impl CompileError {
+    fn code(&self) -> &'static str {
+        match self {
+            CompileError::UnterminatedComment(_) => "E001",
+            CompileError::InvalidNumber(_) => "E002",
+            CompileError::UnexpectedCharacter(_) => "E003",
+            CompileError::UnexpectedToken(_) => "E004",
+            CompileError::MisspelledComparison(_) => "E005",
+            CompileError::UnknownFunction(_) => "E006",
+            CompileError::WrongArgumentCount(_) => "E007",
+            CompileError::DuplicateMatchArm(_) => "E008",
+            CompileError::MisplacedLoopControl(_) => "E009",
+            CompileError::ConflictingStorage(_) => "E010",
+            CompileError::ProgramTooLarge { .. } => "E011",
+            CompileError::TypeMismatch(_) => "E012",
+            CompileError::InvalidMultipleAssignment(_) => "E013",
+            CompileError::InvalidString(_) => "E014",
+            CompileError::InvalidPrintf(_) => "E015",
+            CompileError::InvalidMacro(_) => "E016",
+            CompileError::MisplacedDefer(_) => "E017",
+            CompileError::StaticAssertFailed(_) | CompileError::StaticAssertNotConstant(_) => "E018",
+            CompileError::InvalidPrintWidth(_) => "E019",
+            CompileError::InvalidAssignmentTarget(_) => "E020",
+            CompileError::UnbalancedDirective(_) => "E021",
+            CompileError::InvalidUtf8 { .. } => "E022",
+        }
+    }
+
+    // For bytes that are UTF-8 only up to `valid_up_to`
+    fn invalid_utf8(bytes: &[u8], valid_up_to: usize) -> Self {
+        let line = bytes[..valid_up_to].iter().filter(|&&b| b == b'\n').count() + 1;
+        CompileError::InvalidUtf8 {
+            line,
+            offset: valid_up_to,
+        }
+    }
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "[{}] ", self.code())?;
+        match self {
+            CompileError::ProgramTooLarge { limit } => {
+                write!(f, "Program too large: generated code exceeds {} instructions", limit)
+            }
+            CompileError::InvalidUtf8 { line, offset } => write!(
+                f,
+                "Source is not valid UTF-8 (invalid byte on line {}, byte offset {})",
+                line, offset
+            ),
+            CompileError::UnterminatedComment(message)
+            | CompileError::InvalidNumber(message)
+            | CompileError::UnexpectedCharacter(message)
+            | CompileError::UnexpectedToken(message)
+            | CompileError::MisspelledComparison(message)
+            | CompileError::UnknownFunction(message)
+            | CompileError::WrongArgumentCount(message)
+            | CompileError::DuplicateMatchArm(message)
+            | CompileError::MisplacedLoopControl(message)
+            | CompileError::ConflictingStorage(message)
+            | CompileError::TypeMismatch(message)
+            | CompileError::InvalidMultipleAssignment(message)
+            | CompileError::InvalidString(message)
+            | CompileError::InvalidPrintf(message)
+            | CompileError::InvalidMacro(message)
+            | CompileError::MisplacedDefer(message)
+            | CompileError::StaticAssertFailed(message)
+            | CompileError::StaticAssertNotConstant(message)
+            | CompileError::InvalidPrintWidth(message)
+            | CompileError::InvalidAssignmentTarget(message)
+            | CompileError::UnbalancedDirective(message) => f.write_str(message),
+        }
+    }
+}
+
+// Stop compiling with `error`. It unwinds out of the lexer's and parser's recursion to
+// `catch_compile_error`, which hands it back as a value. This needs panics to unwind: with
+// `panic = "abort"` the quiet hook would swallow the message and the process would just abort,
+// so Cargo.toml pins `panic = "unwind"` and such a build is refused below.
+#[cfg(panic = "abort")]
+compile_error!("compile errors unwind to catch_compile_error; build with panic = \"unwind\"");
+
+fn fail(error: CompileError) -> ! {
+    panic::panic_any(error)
+}
+
+// Run `stage`, returning the CompileError it fails with. Any other panic is a compiler bug and
+// keeps unwinding. The first call installs a process-wide panic hook that stays quiet for
+// CompileError panics and passes every other panic to the hook that was installed before.
+fn catch_compile_error<T>(stage: impl FnOnce() -> T) -> Result<T, CompileError> {
+    // A compile error is reported by whoever catches it, not as a crash on stderr
+    static QUIET_HOOK: Once = Once::new();
+    QUIET_HOOK.call_once(|| {
+        let default_hook = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            if !info.payload().is::<CompileError>() {
+                default_hook(info);
+            }
+        }));
+    });
+    panic::catch_unwind(panic::AssertUnwindSafe(stage)).map_err(|payload| {
+        match payload.downcast::<CompileError>() {
+            Ok(error) => *error,
+            Err(payload) => panic::resume_unwind(payload),
+        }
+    })
+}
+
+// How a Lexer reads its input. The default is what the parser needs.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct LexerOptions {
+    // A tab advances the column to the next multiple of tab_width (1 keeps raw character columns)
+    tab_width: usize,
+    // Match keywords in any case (`PRINT`, `If`); identifiers keep the case they were written in
+    case_insensitive_keywords: bool,
+    // Return whitespace (including line continuations) and comments as tokens instead of skipping
+    // them, so the lexemes of all the tokens put together are the source exactly
+    preserve_trivia: bool,
+}
+
+impl Default for LexerOptions {
+    fn default() -> Self {
+        LexerOptions {
+            tab_width: 1,
+            case_insensitive_keywords: false,
+            preserve_trivia: false,
+        }
+    }
+}
+
 struct Lexer {
     input: Vec<char>,
     position: usize,
+    line: usize,
+    column: usize,
+    options: LexerOptions,
 }
 
 impl Lexer {
@@ -43,13 +266,47 @@ impl Lexer {
         Lexer {
             input: input.chars().collect(),
             position: 0,
+            line: 1,
+            column: 1,
+            options: LexerOptions::default(),
         }
     }
 
+    fn with_options(mut self, options: LexerOptions) -> Self {
+        self.options = LexerOptions {
+            tab_width: options.tab_width.max(1),
+            ..options
+        };
+        self
+    }
+
+    // The source text of the token at `span`
+    fn lexeme(&self, span: Span) -> String {
+        self.input[span.start..span.end].iter().collect()
+    }
+
+    // Start over on a new input with the same options, reusing the buffer of the old one
+    fn reset(&mut self, input: &str) {
+        self.input.clear();
+        self.input.extend(input.chars());
+        self.position = 0;
+        self.line = 1;
+        self.column = 1;
+    }
+
     fn next_char(&mut self) -> Option<char> {
         if self.position < self.input.len() {
             let c = self.input[self.position];
             self.position += 1;
+            if c == '\n' {
+                self.line += 1;
+                self.column = 1;
+            } else if c == '\t' {
+                let tab_width = self.options.tab_width;
+                self.column = (self.column - 1) / tab_width * tab_width + tab_width + 1;
+            } else {
+                self.column += 1;
+            }
             Some(c)
         } else {
             None
@@ -64,65 +321,289 @@ impl Lexer {
         }
     }
 
+    fn peek_next_char(&self) -> Option<char> {
+        self.input.get(self.position + 1).copied()
+    }
+
+    // At whitespace, or a backslash ending a line, which continues it on the next one
+    fn at_space(&self) -> bool {
+        match self.peek_char() {
+            Some('\\') => matches!(self.peek_next_char(), Some('\n' | '\r')),
+            Some(c) => c.is_whitespace(),
+            None => false,
+        }
+    }
+
+    fn at_comment(&self) -> bool {
+        match self.peek_char() {
+            Some('#') => true,
+            Some('/') => self.peek_next_char() == Some('*'),
+            _ => false,
+        }
+    }
+
     fn skip_whitespace(&mut self) {
-        while let Some(c) = self.peek_char() {
-            if c.is_whitespace() {
+        loop {
+            if self.at_space() {
                 self.next_char();
-            } else if c == '#' {
-                // Skip comment until end of line
-                while let Some(ch) = self.peek_char() {
-                    if ch == '\n' {
-                        break;
-                    }
-                    self.next_char();
-                }
+            } else if self.at_comment() {
+                self.skip_comment();
             } else {
                 break;
             }
         }
     }
 
-    fn next_token(&mut self) -> Token {
-        self.skip_whitespace();
+    fn skip_comment(&mut self) {
+        if self.peek_char() == Some('#') {
+            // Skip comment until end of line
+            while let Some(ch) = self.peek_char() {
+                if ch == '\n' {
+                    break;
+                }
+                self.next_char();
+            }
+        } else {
+            self.skip_block_comment();
+        }
+    }
+
+    // One comment, or a run of whitespace, as a token; None when the input isn't at either
+    fn scan_trivia(&mut self) -> Option<Token> {
+        let start = self.position;
+        let comment = self.at_comment();
+        if comment {
+            self.skip_comment();
+        } else {
+            while self.at_space() {
+                self.next_char();
+            }
+        }
+        let text: String = self.input[start..self.position].iter().collect();
+        match (comment, text.is_empty()) {
+            (true, _) => Some(Token::Comment(text)),
+            (false, false) => Some(Token::Whitespace(text)),
+            (false, true) => None,
+        }
+    }
+
+    // Skip a /* ... */ comment, which may span several lines
+    fn skip_block_comment(&mut self) {
+        let (line, column) = (self.line, self.column);
+        self.next_char();
+        self.next_char();
+        loop {
+            match self.next_char() {
+                Some('*') if self.peek_char() == Some('/') => {
+                    self.next_char();
+                    return;
+                }
+                Some(_) => {}
+                None => {
+                    fail(CompileError::UnterminatedComment(format!(
+                        "Unterminated block comment starting at {}:{}",
+                        line,
+                        column
+                    )))
+                }
+            }
+        }
+    }
+
+    fn next_token_with_span(&mut self) -> (Token, Span) {
+        if !self.options.preserve_trivia {
+            self.skip_whitespace();
+        }
+
+        let (start, line, column) = (self.position, self.line, self.column);
+        let trivia = if self.options.preserve_trivia { self.scan_trivia() } else { None };
+        let token = trivia.unwrap_or_else(|| self.scan_token());
+        let span = Span {
+            start,
+            end: self.position,
+            line,
+            column,
+        };
+        (token, span)
+    }
+
+    // The next token that isn't whitespace or a comment, which is all the parser looks at
+    fn next_significant_token_with_span(&mut self) -> (Token, Span) {
+        loop {
+            let (token, span) = self.next_token_with_span();
+            if !token.is_trivia() {
+                return (token, span);
+            }
+        }
+    }
+
+    // Lex the whole input without going through the parser (used by tooling such as --dump-tokens)
+    fn tokenize_with_spans(&mut self) -> Vec<(Token, Span)> {
+        let mut tokens = Vec::new();
+        self.relex_from(&mut tokens, 0);
+        tokens
+    }
+
+    // Bring `tokens`, lexed from an earlier version of the input, up to date with the input
+    // `reset` was last given, when the two differ only from char `offset` on. Tokens that end
+    // before the line holding `offset` are kept; the last of them is lexed again (a comment or
+    // whitespace after it may run into the edited line) and so is everything after it.
+    fn relex_from(&mut self, tokens: &mut Vec<(Token, Span)>, offset: usize) {
+        let offset = offset.min(self.input.len());
+        let line_start = self.input[..offset].iter().rposition(|&c| c == '\n').map_or(0, |i| i + 1);
+        let kept = tokens.iter().take_while(|(_, span)| span.end < line_start).count();
+        let resume = kept.checked_sub(1).map(|last| tokens[last].1);
+        tokens.truncate(kept.saturating_sub(1));
+        (self.position, self.line, self.column) = match resume {
+            Some(span) => (span.start, span.line, span.column),
+            None => (0, 1, 1),
+        };
+        loop {
+            let (token, span) = self.next_token_with_span();
+            let done = token == Token::EOF;
+            tokens.push((token, span));
+            if done {
+                return;
+            }
+        }
+    }
+
+    fn is_ident_start(c: char) -> bool {
+        c.is_alphabetic() || c == '_'
+    }
+
+    fn is_ident_continue(c: char) -> bool {
+        c.is_alphanumeric() || c == '_'
+    }
+
+    fn is_digit_start(c: char) -> bool {
+        c.is_ascii_digit()
+    }
+
+    // The opening quote has been consumed. Strings may not span lines; `\n`, `\t`, `\"` and `\\`
+    // are the escapes.
+    fn scan_string(&mut self) -> Token {
+        let (line, column) = (self.line, self.column - 1);
+        let mut text = String::new();
+        loop {
+            match self.next_char() {
+                Some('"') => return Token::StringLiteral(text),
+                Some('\\') => match self.next_char() {
+                    Some('n') => text.push('\n'),
+                    Some('t') => text.push('\t'),
+                    Some(c @ ('"' | '\\')) => text.push(c),
+                    other => fail(CompileError::InvalidString(format!(
+                        "Unknown escape '\\{}' in string at {}:{}",
+                        other.map_or(String::new(), String::from),
+                        self.line,
+                        self.column - 1
+                    ))),
+                },
+                Some('\n') | None => {
+                    fail(CompileError::InvalidString(format!(
+                        "Unterminated string starting at {}:{}",
+                        line,
+                        column
+                    )))
+                }
+                Some(c) => text.push(c),
+            }
+        }
+    }
 
+    fn scan_token(&mut self) -> Token {
         match self.next_char() {
-            Some(c) if c.is_alphabetic() => {
+            Some(c) if Lexer::is_ident_start(c) => {
                 let mut identifier = c.to_string();
                 while let Some(next) = self.peek_char() {
-                    if next.is_alphanumeric() {
+                    if Lexer::is_ident_continue(next) {
                         identifier.push(self.next_char().unwrap());
                     } else {
                         break;
                     }
                 }
 
-                match identifier.as_str() {
+                let keyword = if self.options.case_insensitive_keywords {
+                    identifier.to_lowercase()
+                } else {
+                    identifier.clone()
+                };
+                match keyword.as_str() {
                     "print" => Token::Print,
                     "if" => Token::If,
                     "else" => Token::Else,
                     "while" => Token::While,
+                    "do" => Token::Do,
                     "for" => Token::For,
                     "in" => Token::In,
                     "break" => Token::Break,
                     "continue" => Token::Continue,
+                    "match" => Token::Match,
                     _ => Token::Identifier(identifier),
                 }
             }
-            Some(c) if c.is_numeric() => {
+            Some(c) if Lexer::is_digit_start(c) => {
+                let (line, column) = (self.line, self.column - 1);
+                let radix = match (c, self.peek_char()) {
+                    ('0', Some('x' | 'X')) => 16,
+                    ('0', Some('b' | 'B')) => 2,
+                    _ => 10,
+                };
                 let mut number = c.to_string();
+                if radix != 10 {
+                    number.push(self.next_char().unwrap());
+                }
+                // `_` may separate digits, as in `1_000` or `0xFF_FF`
                 while let Some(next) = self.peek_char() {
-                    if next.is_numeric() || next == '.' {
+                    if next.is_digit(radix) || next == '_' || (radix == 10 && next == '.') {
                         number.push(self.next_char().unwrap());
                     } else {
                         break;
                     }
                 }
-                Token::Number(number.parse::<f64>().unwrap())
+                // A number running straight into letters (`1abc`) is neither a number nor a name
+                if self.peek_char().is_some_and(Lexer::is_ident_continue) {
+                    while let Some(next) = self.peek_char().filter(|c| Lexer::is_ident_continue(*c)) {
+                        number.push(next);
+                        self.next_char();
+                    }
+                    fail(CompileError::InvalidNumber(format!(
+                        "Invalid number literal '{}' at {}:{}",
+                        number,
+                        line,
+                        column
+                    )));
+                }
+                let prefix = if radix == 10 { 0 } else { 2 };
+                let digits: String = number.chars().skip(prefix).filter(|c| *c != '_').collect();
+                let misplaced_separator = number.ends_with('_')
+                    || ["__", "_.", "._"].iter().any(|pair| number.contains(pair));
+                // Numbers are 64-bit integers, parsed exactly: a literal above i64::MAX, or with a
+                // fraction other than zeros (`1.5`), has no value rather than a rounded one
+                let value = match digits.split_once('.') {
+                    _ if misplaced_separator => None,
+                    Some((_, fraction)) if fraction.chars().any(|c| c != '0') => None,
+                    Some((whole, _)) => whole.parse::<i64>().ok(),
+                    None => i64::from_str_radix(&digits, radix).ok(),
+                };
+                match value {
+                    Some(value) => Token::Number(value),
+                    None => {
+                        fail(CompileError::InvalidNumber(format!(
+                            "Invalid number literal '{}' at {}:{}",
+                            number,
+                            line,
+                            column
+                        )))
+                    }
+                }
             }
+            Some('"') => self.scan_string(),
             Some('+') => Token::Plus,
             Some('-') => Token::Minus,
             Some('*') => Token::Multiply,
             Some('/') => Token::Divide,
+            Some('%') => Token::Modulo,
             Some('=') => {
                 if self.peek_char() == Some('=') {
                     self.next_char();
@@ -136,6 +617,7 @@ impl Lexer {
             Some('{') => Token::LBrace,
             Some('}') => Token::RBrace,
             Some(',') => Token::Comma,
+            Some(':') => Token::Colon,
             Some('<') => {
                 if self.peek_char() == Some('=') {
                     self.next_char();
@@ -157,597 +639,5988 @@ impl Lexer {
                     self.next_char();
                     Token::NotEqual
                 } else {
-                    panic!("Unexpected character: '!'");
+                    Token::Not
+                }
+            }
+            Some(c @ ('&' | '|')) => {
+                if self.peek_char() == Some(c) {
+                    self.next_char();
+                    if c == '&' {
+                        Token::And
+                    } else {
+                        Token::Or
+                    }
+                } else {
+                    fail(CompileError::UnexpectedCharacter(format!(
+                        "Unexpected character: '{}' at {}:{}; the logical operators are '&&' and '||'",
+                        c,
+                        self.line,
+                        self.column - 1
+                    )));
                 }
             }
+            Some('\\') => fail(CompileError::UnexpectedCharacter(format!(
+                "Unexpected '\\' at {}:{}; a line continuation must be the last character on its line",
+                self.line,
+                self.column - 1
+            ))),
             None => Token::EOF,
-            Some(c) => panic!("Unexpected character in input: '{}'", c),
+            Some(c) => fail(CompileError::UnexpectedCharacter(format!(
+                "Unexpected character in input: '{}' at {}:{}",
+                c,
+                self.line,
+                self.column - 1
+            ))),
+        }
+    }
+}
+
+// Where a variable lives, as hinted by `global x = ...` / `local x = ...`
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Storage {
+    // A .bss slot (the default)
+    Global,
+    // A slot in the stack frame set up at program entry
+    Local,
+}
+
+impl Storage {
+    fn keyword(self) -> &'static str {
+        match self {
+            Storage::Global => "global",
+            Storage::Local => "local",
+        }
+    }
+}
+
+// Value type of an expression, checked only under --strict-bool. Comparisons produce a Bool,
+// everything else a Number.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Type {
+    Number,
+    Bool,
+}
+
+impl Type {
+    fn name(self) -> &'static str {
+        match self {
+            Type::Number => "Number",
+            Type::Bool => "Bool",
         }
     }
 }
 
-#[derive(Debug)]
+// Equality is structural.
+#[derive(Debug, Clone, PartialEq)]
 enum ASTNode {
     Assignment {
         variable: String,
         value: Box<ASTNode>,
+        storage: Option<Storage>,
+        origin: Origin,
     },
     BinaryOp {
         left: Box<ASTNode>,
         operator: String,
         right: Box<ASTNode>,
     },
-    Number(f64),
-    Variable(String),
+    Number(i64),
+    // A number literal spelled other than its value's canonical form (`0xFF`, `1_000`), kept so
+    // --format can reproduce it; folding turns it into a plain Number
+    Literal {
+        value: i64,
+        text: String,
+    },
+    // Only found in printf formats, where the fold pass joins `"a" + "b"` into one literal
+    StringLiteral(String),
+    Variable(String, Origin),
+    Call {
+        name: String,
+        args: Vec<ASTNode>,
+    },
+    // `print(x)`, or `print(x, width=5)` to right-align the number in that many columns, or
+    // `print(x, " = ", y)` printing numbers and strings one after another. `fd` is 1, or 2 for
+    // `eprint`.
     Print {
-        expression: Box<ASTNode>,
+        parts: Vec<FormatPart>,
+        width: Option<usize>,
+        fd: u8,
+    },
+    // `printf("%d+%d", a, b)`, with the format string split into parts at compile time
+    Printf {
+        parts: Vec<FormatPart>,
     },
     If {
         condition: Box<ASTNode>,
         then_branch: Vec<ASTNode>,
         else_branch: Vec<ASTNode>,
     },
+    // Loops carry an optional `name:` label that break/continue can refer to
     While {
+        label: Option<String>,
         condition: Box<ASTNode>,
         body: Vec<ASTNode>,
     },
+    DoWhile {
+        label: Option<String>,
+        body: Vec<ASTNode>,
+        condition: Box<ASTNode>,
+    },
+    // `origin` is the loop variable's
     For {
+        label: Option<String>,
         variable: String,
         range_expr: Box<ASTNode>,
         body: Vec<ASTNode>,
+        origin: Origin,
     },
-    Break,
-    Continue,
+    Match {
+        scrutinee: Box<ASTNode>,
+        arms: Vec<(i64, Vec<ASTNode>)>,
+        default: Vec<ASTNode>,
+    },
+    Break(Option<String>),
+    Continue(Option<String>),
+    // `(a = 1, b = 2, a + b)`: evaluated left to right, the value is the last item's
+    Sequence(Vec<ASTNode>),
+    // Inserted by CSE when a statement uses both `x / y` and `x % y`: one division stores the
+    // quotient and the remainder into two temporaries
+    DivMod {
+        dividend: Box<ASTNode>,
+        divisor: Box<ASTNode>,
+        quotient: String,
+        remainder: String,
+    },
+    // `a, b = b, a`: every value is evaluated before any target is stored
+    MultiAssign {
+        targets: Vec<String>,
+        values: Vec<ASTNode>,
+        origins: Vec<Origin>,
+    },
+    // `static_assert(condition)`: the condition must fold to a nonzero constant at compile time.
+    // It generates no code.
+    StaticAssert {
+        condition: Box<ASTNode>,
+        span: Span,
+    },
+    // `defer statement` at the top level: the statement runs when the program ends, after
+    // everything else and before earlier deferred statements
+    Defer(Box<ASTNode>),
+    // A constant -O computed from a compound expression, with that expression's source text
+    Folded {
+        value: i64,
+        source: String,
+    },
+    // A divisor under --safe-div: the program stops with "division by zero at line N" when the
+    // value is zero
+    NonZero {
+        value: Box<ASTNode>,
+        line: usize,
+    },
+    // `macro square(x) = x * x`. Later calls are replaced by the body with the arguments
+    // substituted for the parameters (see expand_macros); the definition itself emits nothing.
+    Macro {
+        name: String,
+        params: Vec<String>,
+        body: Box<ASTNode>,
+    },
+}
+
+// A piece of printed output: literal text, or a number (for printf, the argument printed in place
+// of a `%d`)
+#[derive(Debug, Clone, PartialEq)]
+enum FormatPart {
+    Text(String),
+    Value(ASTNode),
 }
 
 impl ASTNode {
-    fn collect_variables(node: &ASTNode, vars: &mut Vec<String>) {
-        match node {
-            ASTNode::Assignment { variable, .. } => {
-                if !vars.contains(variable) {
-                    vars.push(variable.clone());
+    // Rebuild the node with `f` applied to each of its direct children, statements and
+    // expressions alike
+    fn map_children(self, f: &mut impl FnMut(ASTNode) -> ASTNode) -> ASTNode {
+        fn all(nodes: Vec<ASTNode>, f: &mut impl FnMut(ASTNode) -> ASTNode) -> Vec<ASTNode> {
+            nodes.into_iter().map(&mut *f).collect()
+        }
+        fn values(
+            parts: Vec<FormatPart>,
+            f: &mut impl FnMut(ASTNode) -> ASTNode,
+        ) -> Vec<FormatPart> {
+            parts
+                .into_iter()
+                .map(|part| match part {
+                    FormatPart::Value(value) => FormatPart::Value(f(value)),
+                    text => text,
+                })
+                .collect()
+        }
+        match self {
+            ASTNode::Assignment {
+                variable,
+                value,
+                storage,
+                origin,
+            } => ASTNode::Assignment {
+                variable,
+                value: Box::new(f(*value)),
+                storage,
+                origin,
+            },
+            ASTNode::BinaryOp {
+                left,
+                operator,
+                right,
+            } => {
+                let left = Box::new(f(*left));
+                ASTNode::BinaryOp {
+                    left,
+                    operator,
+                    right: Box::new(f(*right)),
                 }
             }
-            ASTNode::BinaryOp { left, right, .. } => {
-                ASTNode::collect_variables(left, vars);
-                ASTNode::collect_variables(right, vars);
-            }
+            ASTNode::Call { name, args } => ASTNode::Call {
+                name,
+                args: all(args, f),
+            },
+            ASTNode::Print { parts, width, fd } => ASTNode::Print {
+                parts: values(parts, f),
+                width,
+                fd,
+            },
+            ASTNode::Printf { parts } => ASTNode::Printf {
+                parts: values(parts, f),
+            },
             ASTNode::If {
                 condition,
                 then_branch,
                 else_branch,
             } => {
-                ASTNode::collect_variables(condition, vars);
-                for stmt in then_branch.iter().chain(else_branch.iter()) {
-                    ASTNode::collect_variables(stmt, vars);
+                let condition = Box::new(f(*condition));
+                let then_branch = all(then_branch, f);
+                ASTNode::If {
+                    condition,
+                    then_branch,
+                    else_branch: all(else_branch, f),
                 }
             }
-            ASTNode::While { condition, body } => {
-                ASTNode::collect_variables(condition, vars);
-                for stmt in body {
-                    ASTNode::collect_variables(stmt, vars);
+            ASTNode::While { label, condition, body } => {
+                let condition = Box::new(f(*condition));
+                ASTNode::While {
+                    label,
+                    condition,
+                    body: all(body, f),
                 }
             }
-            ASTNode::For { variable, range_expr, body } => {
-                if !vars.contains(variable) {
-                    vars.push(variable.clone());
+            ASTNode::DoWhile { label, body, condition } => {
+                let body = all(body, f);
+                ASTNode::DoWhile {
+                    label,
+                    body,
+                    condition: Box::new(f(*condition)),
                 }
-                ASTNode::collect_variables(range_expr, vars);
-                for stmt in body {
-                    ASTNode::collect_variables(stmt, vars);
+            }
+            ASTNode::For {
+                label,
+                variable,
+                range_expr,
+                body,
+                origin,
+            } => {
+                let range_expr = Box::new(f(*range_expr));
+                ASTNode::For {
+                    label,
+                    variable,
+                    range_expr,
+                    body: all(body, f),
+                    origin,
                 }
             }
-            _ => {}
+            ASTNode::Match {
+                scrutinee,
+                arms,
+                default,
+            } => {
+                let scrutinee = Box::new(f(*scrutinee));
+                let arms = arms
+                    .into_iter()
+                    .map(|(value, body)| (value, all(body, f)))
+                    .collect();
+                ASTNode::Match {
+                    scrutinee,
+                    arms,
+                    default: all(default, f),
+                }
+            }
+            ASTNode::Sequence(items) => ASTNode::Sequence(all(items, f)),
+            ASTNode::DivMod {
+                dividend,
+                divisor,
+                quotient,
+                remainder,
+            } => {
+                let dividend = Box::new(f(*dividend));
+                ASTNode::DivMod {
+                    dividend,
+                    divisor: Box::new(f(*divisor)),
+                    quotient,
+                    remainder,
+                }
+            }
+            ASTNode::MultiAssign {
+                targets,
+                values,
+                origins,
+            } => ASTNode::MultiAssign {
+                targets,
+                values: all(values, f),
+                origins,
+            },
+            ASTNode::NonZero { value, line } => ASTNode::NonZero {
+                value: Box::new(f(*value)),
+                line,
+            },
+            ASTNode::Defer(statement) => ASTNode::Defer(Box::new(f(*statement))),
+            ASTNode::StaticAssert { condition, span } => ASTNode::StaticAssert {
+                condition: Box::new(f(*condition)),
+                span,
+            },
+            ASTNode::Macro { name, params, body } => ASTNode::Macro {
+                name,
+                params,
+                body: Box::new(f(*body)),
+            },
+            leaf => leaf,
         }
     }
-}
-
-struct Parser {
-    lexer: Lexer,
-    current_token: Token,
-}
 
-impl Parser {
-    fn new(mut lexer: Lexer) -> Self {
-        let current_token = lexer.next_token();
-        Parser {
-            lexer,
-            current_token,
+    // Call `f` on each of the node's direct children in the order map_children visits them,
+    // without rebuilding the node
+    fn for_each_child(&self, f: &mut impl FnMut(&ASTNode)) {
+        fn values(parts: &[FormatPart], f: &mut impl FnMut(&ASTNode)) {
+            for part in parts {
+                if let FormatPart::Value(value) = part {
+                    f(value);
+                }
+            }
         }
-    }
-
-    fn eat(&mut self, token: Token) {
-        if self.current_token == token {
-            self.current_token = self.lexer.next_token();
-        } else {
-            panic!(
-                "Unexpected token: {:?}, expected: {:?}",
-                self.current_token, token
-            );
+        match self {
+            ASTNode::Assignment { value, .. }
+            | ASTNode::NonZero { value, .. }
+            | ASTNode::Defer(value)
+            | ASTNode::StaticAssert { condition: value, .. }
+            | ASTNode::Macro { body: value, .. } => f(value),
+            ASTNode::BinaryOp { left, right, .. } => {
+                f(left);
+                f(right);
+            }
+            ASTNode::DivMod { dividend, divisor, .. } => {
+                f(dividend);
+                f(divisor);
+            }
+            ASTNode::Call { args: nodes, .. }
+            | ASTNode::Sequence(nodes)
+            | ASTNode::MultiAssign { values: nodes, .. } => nodes.iter().for_each(f),
+            ASTNode::Print { parts, .. } | ASTNode::Printf { parts } => values(parts, f),
+            ASTNode::If {
+                condition,
+                then_branch,
+                else_branch,
+            } => {
+                f(condition);
+                then_branch.iter().for_each(&mut *f);
+                else_branch.iter().for_each(f);
+            }
+            ASTNode::While { condition, body, .. }
+            | ASTNode::For {
+                range_expr: condition,
+                body,
+                ..
+            } => {
+                f(condition);
+                body.iter().for_each(f);
+            }
+            ASTNode::DoWhile { body, condition, .. } => {
+                body.iter().for_each(&mut *f);
+                f(condition);
+            }
+            ASTNode::Match {
+                scrutinee,
+                arms,
+                default,
+            } => {
+                f(scrutinee);
+                for (_, body) in arms {
+                    body.iter().for_each(&mut *f);
+                }
+                default.iter().for_each(f);
+            }
+            _ => {}
         }
     }
 
-    fn parse_term(&mut self) -> ASTNode {
-        match self.current_token.clone() {
-            Token::Number(value) => {
-                self.eat(Token::Number(value));
-                ASTNode::Number(value)
-            }
-            Token::Identifier(name) => {
-                self.eat(Token::Identifier(name.clone()));
-                // Check if this is a function call
-                if name == "range" && self.current_token == Token::LParen {
-                    self.eat(Token::LParen);
-                    let arg = self.parse_expression();
-                    self.eat(Token::RParen);
-                    arg  // For now, range(n) just returns n
-                } else {
-                    ASTNode::Variable(name)
+    // Replace calls to macros defined earlier with their bodies. A macro's body is expanded when
+    // it is defined, so a substituted body never needs expanding again.
+    fn expand_macros(self, macros: &mut HashMap<String, (Vec<String>, ASTNode)>) -> ASTNode {
+        match self {
+            ASTNode::Macro { name, params, body } => {
+                let body = body.expand_macros(macros);
+                macros.insert(name.clone(), (params.clone(), body.clone()));
+                ASTNode::Macro {
+                    name,
+                    params,
+                    body: Box::new(body),
                 }
             }
-            Token::LParen => {
-                self.eat(Token::LParen);
-                let expr = self.parse_expression();
-                self.eat(Token::RParen);
-                expr
+            ASTNode::Call { name, args } if macros.contains_key(&name) => {
+                let args: Vec<ASTNode> = args.into_iter().map(|arg| arg.expand_macros(macros)).collect();
+                let (params, body) = &macros[&name];
+                let bindings: HashMap<&str, ASTNode> = params.iter().map(String::as_str).zip(args).collect();
+                body.clone().substitute(&bindings)
             }
-            _ => panic!("Unexpected token in term: {:?}", self.current_token),
+            node => node.map_children(&mut |child| child.expand_macros(macros)),
         }
     }
 
-    fn parse_expression(&mut self) -> ASTNode {
-        let mut left = self.parse_term();
+    fn substitute(self, bindings: &HashMap<&str, ASTNode>) -> ASTNode {
+        match self {
+            ASTNode::Variable(name, origin) => match bindings.get(name.as_str()) {
+                Some(arg) => arg.clone(),
+                None => ASTNode::Variable(name, origin),
+            },
+            node => node.map_children(&mut |child| child.substitute(bindings)),
+        }
+    }
 
-        while matches!(
-            self.current_token,
-            Token::Plus | Token::Minus | Token::Multiply | Token::Divide
-        ) {
-            let operator = match self.current_token {
-                Token::Plus => {
-                    self.eat(Token::Plus);
-                    "+"
-                }
-                Token::Minus => {
-                    self.eat(Token::Minus);
-                    "-"
-                }
-                Token::Multiply => {
-                    self.eat(Token::Multiply);
-                    "*"
-                }
-                Token::Divide => {
-                    self.eat(Token::Divide);
-                    "/"
-                }
-                _ => unreachable!(),
+    // The value of a constant: a number, or an expression -O folded to one
+    fn constant(&self) -> Option<i64> {
+        match self {
+            ASTNode::Number(value) | ASTNode::Literal { value, .. } | ASTNode::Folded { value, .. } => {
+                Some(*value)
             }
-            .to_string();
+            _ => None,
+        }
+    }
 
-            let right = self.parse_term();
-            left = ASTNode::BinaryOp {
-                left: Box::new(left),
-                operator,
-                right: Box::new(right),
-            };
+    // The Rust expression that constructs this node, for pasting into tests (--emit=ast-rust)
+    fn to_rust(&self) -> String {
+        fn string(text: &str) -> String {
+            format!("{:?}.to_string()", text)
+        }
+        fn boxed(node: &ASTNode) -> String {
+            format!("Box::new({})", node.to_rust())
+        }
+        fn nodes(nodes: &[ASTNode]) -> String {
+            let nodes: Vec<String> = nodes.iter().map(ASTNode::to_rust).collect();
+            format!("vec![{}]", nodes.join(", "))
+        }
+        fn label(label: &Option<String>) -> String {
+            label.as_ref().map_or("None".to_string(), |label| format!("Some({})", string(label)))
+        }
+        fn format_parts(parts: &[FormatPart]) -> String {
+            let parts: Vec<String> = parts
+                .iter()
+                .map(|part| match part {
+                    FormatPart::Text(text) => format!("FormatPart::Text({})", string(text)),
+                    FormatPart::Value(value) => format!("FormatPart::Value({})", value.to_rust()),
+                })
+                .collect();
+            parts.join(", ")
         }
 
-        left
-    }
-
-    fn parse_assignment(&mut self) -> ASTNode {
-        if let Token::Identifier(name) = self.current_token.clone() {
-            self.eat(Token::Identifier(name.clone()));
-            self.eat(Token::Assign);
-            let value = self.parse_expression();
+        match self {
             ASTNode::Assignment {
-                variable: name,
-                value: Box::new(value),
+                variable,
+                value,
+                storage,
+                origin,
+            } => format!(
+                "ASTNode::Assignment {{ variable: {}, value: {}, storage: {}, origin: {:?} }}",
+                string(variable),
+                boxed(value),
+                storage.map_or("None".to_string(), |storage| format!(
+                    "Some(Storage::{:?})",
+                    storage
+                )),
+                origin
+            ),
+            ASTNode::BinaryOp {
+                left,
+                operator,
+                right,
+            } => format!(
+                "ASTNode::BinaryOp {{ left: {}, operator: {}, right: {} }}",
+                boxed(left),
+                string(operator),
+                boxed(right)
+            ),
+            ASTNode::Number(value) => format!("ASTNode::Number({})", value),
+            ASTNode::Literal { value, text } => format!(
+                "ASTNode::Literal {{ value: {}, text: {} }}",
+                value,
+                string(text)
+            ),
+            ASTNode::StringLiteral(text) => format!("ASTNode::StringLiteral({})", string(text)),
+            ASTNode::Variable(name, origin) => {
+                format!("ASTNode::Variable({}, {:?})", string(name), origin)
+            }
+            ASTNode::Call { name, args } => {
+                format!("ASTNode::Call {{ name: {}, args: {} }}", string(name), nodes(args))
+            }
+            ASTNode::Print { parts, width, fd } => format!(
+                "ASTNode::Print {{ parts: vec![{}], width: {:?}, fd: {} }}",
+                format_parts(parts),
+                width,
+                fd
+            ),
+            ASTNode::Printf { parts } => {
+                format!("ASTNode::Printf {{ parts: vec![{}] }}", format_parts(parts))
+            }
+            ASTNode::If {
+                condition,
+                then_branch,
+                else_branch,
+            } => format!(
+                "ASTNode::If {{ condition: {}, then_branch: {}, else_branch: {} }}",
+                boxed(condition),
+                nodes(then_branch),
+                nodes(else_branch)
+            ),
+            ASTNode::While {
+                label: name,
+                condition,
+                body,
+            } => format!(
+                "ASTNode::While {{ label: {}, condition: {}, body: {} }}",
+                label(name),
+                boxed(condition),
+                nodes(body)
+            ),
+            ASTNode::DoWhile {
+                label: name,
+                body,
+                condition,
+            } => format!(
+                "ASTNode::DoWhile {{ label: {}, body: {}, condition: {} }}",
+                label(name),
+                nodes(body),
+                boxed(condition)
+            ),
+            ASTNode::For {
+                label: name,
+                variable,
+                range_expr,
+                body,
+                origin,
+            } => format!(
+                "ASTNode::For {{ label: {}, variable: {}, range_expr: {}, body: {}, origin: {:?} \
+                 }}",
+                label(name),
+                string(variable),
+                boxed(range_expr),
+                nodes(body),
+                origin
+            ),
+            ASTNode::Match {
+                scrutinee,
+                arms,
+                default,
+            } => {
+                let arms: Vec<String> = arms
+                    .iter()
+                    .map(|(value, body)| format!("({}, {})", value, nodes(body)))
+                    .collect();
+                format!(
+                    "ASTNode::Match {{ scrutinee: {}, arms: vec![{}], default: {} }}",
+                    boxed(scrutinee),
+                    arms.join(", "),
+                    nodes(default)
+                )
+            }
+            ASTNode::Break(name) => format!("ASTNode::Break({})", label(name)),
+            ASTNode::Continue(name) => format!("ASTNode::Continue({})", label(name)),
+            ASTNode::Sequence(items) => format!("ASTNode::Sequence({})", nodes(items)),
+            ASTNode::DivMod {
+                dividend,
+                divisor,
+                quotient,
+                remainder,
+            } => format!(
+                "ASTNode::DivMod {{ dividend: {}, divisor: {}, quotient: {}, remainder: {} }}",
+                boxed(dividend),
+                boxed(divisor),
+                string(quotient),
+                string(remainder)
+            ),
+            ASTNode::MultiAssign {
+                targets,
+                values,
+                origins,
+            } => {
+                let targets: Vec<String> = targets.iter().map(|target| string(target)).collect();
+                format!(
+                    "ASTNode::MultiAssign {{ targets: vec![{}], values: {}, origins: vec!{:?} }}",
+                    targets.join(", "),
+                    nodes(values),
+                    origins
+                )
+            }
+            ASTNode::StaticAssert { condition, span } => format!(
+                "ASTNode::StaticAssert {{ condition: {}, span: {:?} }}",
+                boxed(condition),
+                span
+            ),
+            ASTNode::Defer(statement) => format!("ASTNode::Defer({})", boxed(statement)),
+            ASTNode::Folded { value, source } => format!(
+                "ASTNode::Folded {{ value: {}, source: {} }}",
+                value,
+                string(source)
+            ),
+            ASTNode::NonZero { value, line } => {
+                format!("ASTNode::NonZero {{ value: {}, line: {} }}", boxed(value), line)
+            }
+            ASTNode::Macro { name, params, body } => {
+                let params: Vec<String> = params.iter().map(|param| string(param)).collect();
+                format!(
+                    "ASTNode::Macro {{ name: {}, params: vec![{}], body: {} }}",
+                    string(name),
+                    params.join(", "),
+                    boxed(body)
+                )
             }
-        } else {
-            panic!("Expected an identifier for assignment");
         }
     }
 
-    fn parse_comparison(&mut self) -> ASTNode {
-        let left = self.parse_expression();
+    // Argument expressions of a printf, in order
+    fn format_values(parts: &[FormatPart]) -> impl Iterator<Item = &ASTNode> {
+        parts.iter().filter_map(|part| match part {
+            FormatPart::Value(value) => Some(value),
+            FormatPart::Text(_) => None,
+        })
+    }
 
-        match self.current_token {
-            Token::Equal => {
-                self.eat(Token::Equal);
-                let right = self.parse_expression();
-                ASTNode::BinaryOp {
-                    left: Box::new(left),
-                    operator: "==".to_string(),
-                    right: Box::new(right),
+    // This node and every node below it
+    fn count_nodes(&self) -> usize {
+        let mut count = 1;
+        self.for_each_child(&mut |child| count += child.count_nodes());
+        count
+    }
+
+    fn collect_variables(node: &ASTNode, vars: &mut Vec<String>) {
+        match node {
+            ASTNode::Assignment { variable, value, .. } => {
+                if !vars.contains(variable) {
+                    vars.push(variable.clone());
                 }
+                // The value may assign too, e.g. `x = (y = 2, y + 1)`
+                ASTNode::collect_variables(value, vars);
             }
-            Token::NotEqual => {
-                self.eat(Token::NotEqual);
-                let right = self.parse_expression();
-                ASTNode::BinaryOp {
-                    left: Box::new(left),
-                    operator: "!=".to_string(),
-                    right: Box::new(right),
+            ASTNode::DivMod {
+                dividend,
+                divisor,
+                quotient,
+                remainder,
+            } => {
+                for temp in [quotient, remainder] {
+                    if !vars.contains(temp) {
+                        vars.push(temp.clone());
+                    }
                 }
+                ASTNode::collect_variables(dividend, vars);
+                ASTNode::collect_variables(divisor, vars);
             }
-            Token::LessThan => {
-                self.eat(Token::LessThan);
-                let right = self.parse_expression();
-                ASTNode::BinaryOp {
-                    left: Box::new(left),
-                    operator: "<".to_string(),
-                    right: Box::new(right),
+            ASTNode::MultiAssign { targets, values, .. } => {
+                for target in targets {
+                    if !vars.contains(target) {
+                        vars.push(target.clone());
+                    }
+                }
+                for value in values {
+                    ASTNode::collect_variables(value, vars);
                 }
             }
-            Token::GreaterThan => {
-                self.eat(Token::GreaterThan);
-                let right = self.parse_expression();
-                ASTNode::BinaryOp {
-                    left: Box::new(left),
-                    operator: ">".to_string(),
-                    right: Box::new(right),
+            ASTNode::NonZero { value: expression, .. } | ASTNode::Defer(expression) => {
+                ASTNode::collect_variables(expression, vars)
+            }
+            ASTNode::Print { parts, .. } | ASTNode::Printf { parts } => {
+                for value in ASTNode::format_values(parts) {
+                    ASTNode::collect_variables(value, vars);
                 }
             }
-            Token::LessEqual => {
-                self.eat(Token::LessEqual);
-                let right = self.parse_expression();
-                ASTNode::BinaryOp {
-                    left: Box::new(left),
-                    operator: "<=".to_string(),
-                    right: Box::new(right),
+            ASTNode::Call { args: items, .. } | ASTNode::Sequence(items) => {
+                for item in items {
+                    ASTNode::collect_variables(item, vars);
                 }
             }
-            Token::GreaterEqual => {
-                self.eat(Token::GreaterEqual);
-                let right = self.parse_expression();
-                ASTNode::BinaryOp {
-                    left: Box::new(left),
-                    operator: ">=".to_string(),
-                    right: Box::new(right),
+            ASTNode::BinaryOp { left, right, .. } => {
+                ASTNode::collect_variables(left, vars);
+                ASTNode::collect_variables(right, vars);
+            }
+            ASTNode::If {
+                condition,
+                then_branch,
+                else_branch,
+            } => {
+                ASTNode::collect_variables(condition, vars);
+                for stmt in then_branch.iter().chain(else_branch.iter()) {
+                    ASTNode::collect_variables(stmt, vars);
+                }
+            }
+            ASTNode::While { condition, body, .. } | ASTNode::DoWhile { body, condition, .. } => {
+                ASTNode::collect_variables(condition, vars);
+                for stmt in body {
+                    ASTNode::collect_variables(stmt, vars);
                 }
             }
-            _ => left,
+            ASTNode::For { variable, range_expr, body, .. } => {
+                if !vars.contains(variable) {
+                    vars.push(variable.clone());
+                }
+                ASTNode::collect_variables(range_expr, vars);
+                for stmt in body {
+                    ASTNode::collect_variables(stmt, vars);
+                }
+            }
+            ASTNode::Match {
+                scrutinee,
+                arms,
+                default,
+            } => {
+                ASTNode::collect_variables(scrutinee, vars);
+                for stmt in arms.iter().flat_map(|(_, body)| body).chain(default.iter()) {
+                    ASTNode::collect_variables(stmt, vars);
+                }
+            }
+            _ => {}
         }
     }
-    fn parse_if(&mut self) -> ASTNode {
-        self.eat(Token::If);
-        self.eat(Token::LParen);
-        let condition = self.parse_comparison(); // handle comparisons here
-        self.eat(Token::RParen);
-        self.eat(Token::LBrace);
-        let then_branch = self.parse_block();
-        self.eat(Token::RBrace);
+}
 
-        let else_branch = if self.current_token == Token::Else {
-            self.eat(Token::Else);
-            self.eat(Token::LBrace);
-            let branch = self.parse_block();
-            self.eat(Token::RBrace);
-            branch
-        } else {
-            vec![]
-        };
+// How a binary operator groups with itself: `a - b - c` is `(a - b) - c`, while comparisons
+// don't chain at all
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Associativity {
+    Left,
+    Right,
+    None,
+}
 
-        ASTNode::If {
-            condition: Box::new(condition),
-            then_branch,
-            else_branch,
-        }
-    }
-    fn parse_while(&mut self) -> ASTNode {
-        self.eat(Token::While);
-        self.eat(Token::LParen);
-        let condition = self.parse_comparison();
-        self.eat(Token::RParen);
-        self.eat(Token::LBrace);
-        let body = self.parse_block();
-        self.eat(Token::RBrace);
+// The binary operators with their default binding power (higher binds tighter)
+const BINARY_OPERATORS: [(&str, u8, Associativity); 13] = [
+    ("||", 1, Associativity::Left),
+    ("&&", 2, Associativity::Left),
+    ("==", 3, Associativity::None),
+    ("!=", 3, Associativity::None),
+    ("<", 3, Associativity::None),
+    (">", 3, Associativity::None),
+    ("<=", 3, Associativity::None),
+    (">=", 3, Associativity::None),
+    ("+", 4, Associativity::Left),
+    ("-", 4, Associativity::Left),
+    ("*", 5, Associativity::Left),
+    ("/", 5, Associativity::Left),
+    ("%", 5, Associativity::Left),
+];
 
-        ASTNode::While {
-            condition: Box::new(condition),
-            body,
-        }
-    }
-    fn parse_for(&mut self) -> ASTNode {
-        self.eat(Token::For);
-        self.eat(Token::LParen);
-        if let Token::Identifier(name) = self.current_token.clone() {
-            self.eat(Token::Identifier(name.clone()));
-            self.eat(Token::In);
-            let range_expr = self.parse_expression();
-            self.eat(Token::RParen);
-            self.eat(Token::LBrace);
-            let body = self.parse_block();
-            self.eat(Token::RBrace);
-            ASTNode::For {
-                variable: name,
-                range_expr: Box::new(range_expr),
-                body,
+fn default_precedence() -> HashMap<String, (u8, Associativity)> {
+    BINARY_OPERATORS
+        .iter()
+        .map(|&(operator, level, associativity)| (operator.to_string(), (level, associativity)))
+        .collect()
+}
+
+// A variable of a parsed input, with where it is assigned and where it is read
+#[derive(Debug, Clone, PartialEq)]
+struct Symbol {
+    name: String,
+    assignments: Vec<Span>,
+    references: Vec<Span>,
+}
+
+// The variables of a parsed input in order of first appearance
+#[derive(Debug, Clone, Default, PartialEq)]
+struct SymbolIndex {
+    symbols: Vec<Symbol>,
+}
+
+impl SymbolIndex {
+    fn record(&mut self, name: &str, origin: Origin, assigned: bool) {
+        let Origin(Some(span)) = origin else { return };
+        let index = match self.symbols.iter().position(|symbol| symbol.name == name) {
+            Some(index) => index,
+            None => {
+                self.symbols.push(Symbol {
+                    name: name.to_string(),
+                    assignments: Vec::new(),
+                    references: Vec::new(),
+                });
+                self.symbols.len() - 1
             }
+        };
+        let symbol = &mut self.symbols[index];
+        if assigned {
+            symbol.assignments.push(span);
         } else {
-            panic!("Expected an identifier for 'for' loop variable");
-        }
-    }
-    fn parse_block(&mut self) -> Vec<ASTNode> {
-        let mut statements = Vec::new();
-        while self.current_token != Token::RBrace && self.current_token != Token::EOF {
-            statements.push(self.parse_statement());
+            symbol.references.push(span);
         }
-        statements
     }
 
-    fn parse_statement(&mut self) -> ASTNode {
-        match self.current_token {
-            Token::If => self.parse_if(),
-            Token::While => self.parse_while(),
-            Token::For => self.parse_for(),
-            Token::Print => {
-                self.eat(Token::Print);
-                self.eat(Token::LParen);
-                let expr = self.parse_comparison(); // Updated to handle comparisons
-                self.eat(Token::RParen);
-                ASTNode::Print {
-                    expression: Box::new(expr),
+    // Record the variables of `node`, leaving out `params`: in a macro body those stand for the
+    // arguments, not for variables
+    fn visit(&mut self, node: &ASTNode, params: &[String]) {
+        match node {
+            ASTNode::Variable(name, origin) if !params.contains(name) => {
+                self.record(name, *origin, false)
+            }
+            ASTNode::Assignment { variable, value, origin, .. } => {
+                self.record(variable, *origin, true);
+                self.visit(value, params);
+            }
+            ASTNode::MultiAssign { targets, values, origins } => {
+                for (target, origin) in targets.iter().zip(origins) {
+                    self.record(target, *origin, true);
+                }
+                for value in values {
+                    self.visit(value, params);
                 }
             }
-            Token::Identifier(_) => self.parse_assignment(),
-            Token::Break => {
-                self.eat(Token::Break);
-                ASTNode::Break
+            ASTNode::For { variable, range_expr, body, origin, .. } => {
+                self.record(variable, *origin, true);
+                self.visit(range_expr, params);
+                for stmt in body {
+                    self.visit(stmt, params);
+                }
             }
-            Token::Continue => {
-                self.eat(Token::Continue);
-                ASTNode::Continue
+            ASTNode::Macro { params: names, body, .. } => self.visit(body, names),
+            ASTNode::DivMod { dividend, divisor, .. } => {
+                self.visit(dividend, params);
+                self.visit(divisor, params);
             }
-            _ => panic!(
-                "Unexpected token: {:?}. Expected a statement.",
-                self.current_token
-            ),
+            ASTNode::NonZero { value: expression, .. }
+            | ASTNode::Defer(expression)
+            | ASTNode::StaticAssert { condition: expression, .. } => self.visit(expression, params),
+            ASTNode::Print { parts, .. } | ASTNode::Printf { parts } => {
+                for value in ASTNode::format_values(parts) {
+                    self.visit(value, params);
+                }
+            }
+            ASTNode::Call { args: items, .. } | ASTNode::Sequence(items) => {
+                for item in items {
+                    self.visit(item, params);
+                }
+            }
+            ASTNode::BinaryOp { left, right, .. } => {
+                self.visit(left, params);
+                self.visit(right, params);
+            }
+            ASTNode::If {
+                condition,
+                then_branch,
+                else_branch,
+            } => {
+                self.visit(condition, params);
+                for stmt in then_branch.iter().chain(else_branch.iter()) {
+                    self.visit(stmt, params);
+                }
+            }
+            ASTNode::While { condition, body, .. } => {
+                self.visit(condition, params);
+                for stmt in body {
+                    self.visit(stmt, params);
+                }
+            }
+            ASTNode::DoWhile { body, condition, .. } => {
+                for stmt in body {
+                    self.visit(stmt, params);
+                }
+                self.visit(condition, params);
+            }
+            ASTNode::Match {
+                scrutinee,
+                arms,
+                default,
+            } => {
+                self.visit(scrutinee, params);
+                for stmt in arms.iter().flat_map(|(_, body)| body).chain(default.iter()) {
+                    self.visit(stmt, params);
+                }
+            }
+            _ => {}
         }
     }
+}
 
-    fn parse(&mut self) -> Vec<ASTNode> {
-        let mut nodes = Vec::new();
-        while self.current_token != Token::EOF {
-            nodes.push(self.parse_statement());
-        }
-        nodes
+// Index the variables of parsed (not yet macro-expanded) statements by the source spans the
+// parser gave them. Nodes without an origin, which the compiler made up, are left out.
+fn build_symbol_index(nodes: &[ASTNode]) -> SymbolIndex {
+    let mut index = SymbolIndex::default();
+    for node in nodes {
+        index.visit(node, &[]);
+    }
+    for symbol in &mut index.symbols {
+        symbol.assignments.sort_by_key(|span| span.start);
+        symbol.references.sort_by_key(|span| span.start);
     }
+    let first = |symbol: &Symbol| {
+        symbol.assignments.iter().chain(&symbol.references).map(|span| span.start).min()
+    };
+    index.symbols.sort_by_key(first);
+    index
 }
 
-struct CodeGenerator {
-    output: File,
-    label_counter: usize,
+struct Parser {
+    lexer: Lexer,
+    current_token: Token,
+    current_span: Span,
+    previous_end: usize,
+    // Source span of each top-level statement returned by parse(), in order
+    statement_spans: Vec<Span>,
+    // Span of each statement right after a `break` or `continue` in its block, which
+    // remove_unreachable will drop
+    unreachable_spans: Vec<Span>,
+    // Labels of the loops enclosing the current statement, innermost last
+    loop_labels: Vec<Option<String>>,
+    // Storage hint given for each variable, with where it was first given
+    storage_hints: HashMap<String, (Storage, Span)>,
+    // With --strict-bool, conditions must be Bool, arithmetic operands Number, and each variable
+    // keeps the type of its first assignment
+    strict_bool: bool,
+    variable_types: HashMap<String, Type>,
+    // Functions calls may name, with their arities
+    builtins: HashMap<String, Builtin>,
+    // Macros defined so far, with their arity and the type of their body
+    macros: HashMap<String, (usize, Type)>,
+    // Wrap divisors in NonZero checks
+    safe_div: bool,
+    // Binding power and associativity of each binary operator, consulted by parse_binary
+    precedence: HashMap<String, (u8, Associativity)>,
+    // Blocks (or deferred statements) the parser is inside; 0 at the top level
+    depth: usize,
 }
 
-impl CodeGenerator {
-    fn new(output_path: &str) -> Self {
-        let file = File::create(output_path).expect("Unable to create file");
-        CodeGenerator {
-            output: file,
-            label_counter: 0,
+impl Parser {
+    fn new(mut lexer: Lexer) -> Self {
+        let (current_token, current_span) = lexer.next_significant_token_with_span();
+        Parser {
+            lexer,
+            current_token,
+            current_span,
+            previous_end: 0,
+            statement_spans: Vec::new(),
+            unreachable_spans: Vec::new(),
+            loop_labels: Vec::new(),
+            storage_hints: HashMap::new(),
+            strict_bool: false,
+            variable_types: HashMap::new(),
+            builtins: default_builtins(),
+            macros: HashMap::new(),
+            safe_div: false,
+            precedence: default_precedence(),
+            depth: 0,
         }
     }
 
-    fn emit(&mut self, instruction: &str) {
-        writeln!(self.output, "{}", instruction).expect("Unable to write to file");
+    // Parse a new input with the same options and builtins, forgetting everything learned from the
+    // previous one (its variables, macros, statement spans and symbols)
+    fn reset(&mut self, input: &str) {
+        self.lexer.reset(input);
+        (self.current_token, self.current_span) = self.lexer.next_significant_token_with_span();
+        self.previous_end = 0;
+        self.statement_spans.clear();
+        self.unreachable_spans.clear();
+        self.loop_labels.clear();
+        self.storage_hints.clear();
+        self.variable_types.clear();
+        self.macros.clear();
+        self.depth = 0;
     }
 
-    fn emit_header(&mut self, variables: &[String]) {
-        self.emit("section .bss");
-        for var in variables {
-            self.emit(&format!("{} resq 1", var));
-        }
-        self.emit("buffer resb 20");
+    // Replace the default operator precedence, e.g. to experiment with other groupings
+    fn with_precedence(mut self, precedence: HashMap<String, (u8, Associativity)>) -> Self {
+        self.precedence = precedence;
+        self
+    }
 
-        self.emit("section .data");
-        self.emit("newline db 0xA, 0");
+    fn with_builtins(mut self, builtins: HashMap<String, Builtin>) -> Self {
+        self.builtins = builtins;
+        self
+    }
 
-        self.emit("section .text");
-        self.emit("global _start");
-        self.emit("_start:");
+    fn with_safe_div(mut self) -> Self {
+        self.safe_div = true;
+        self
     }
 
-    fn emit_footer(&mut self) {
-        self.emit("    mov rax, 60       ; syscall: exit");
-        self.emit("    xor rdi, rdi      ; return code: 0");
-        self.emit("    syscall");
+    fn with_strict_bool(mut self) -> Self {
+        self.strict_bool = true;
+        self
+    }
 
-        self.emit("; Subroutine to convert an integer in RAX to a string in the buffer");
-        self.emit("int_to_string:");
-        self.emit("    xor rdx, rdx              ; Clear rdx (remainder)");
-        self.emit("    mov rbx, 10               ; Divisor for decimal system");
-        self.emit("    add rcx, 20               ; Move pointer to the end of the buffer");
-        self.emit("    dec rcx                   ; Reserve space for the last character");
-        self.emit(".convert_loop:");
-        self.emit("    xor rdx, rdx              ; Clear rdx before division");
-        self.emit("    div rbx                   ; Divide rax by 10, remainder in rdx");
-        self.emit("    add dl, '0'               ; Convert remainder to ASCII");
-        self.emit("    mov [rcx], dl             ; Store the ASCII character in the buffer");
-        self.emit("    dec rcx                   ; Move to the previous position in the buffer");
-        self.emit("    test rax, rax             ; Check if quotient is 0");
-        self.emit("    jnz .convert_loop         ; Repeat if not 0");
-        self.emit("    inc rcx                   ; Adjust pointer to the start of the string");
+    fn type_of(&self, node: &ASTNode) -> Type {
+        match node {
+            ASTNode::BinaryOp { operator, .. }
+                if matches!(
+                    operator.as_str(),
+                    "==" | "!=" | "<" | ">" | "<=" | ">=" | "&&" | "||"
+                ) =>
+            {
+                Type::Bool
+            }
+            ASTNode::Variable(name, _) => {
+                self.variable_types.get(name).copied().unwrap_or(Type::Number)
+            }
+            ASTNode::Assignment { value, .. } | ASTNode::NonZero { value, .. } => self.type_of(value),
+            ASTNode::Sequence(items) => items.last().map_or(Type::Number, |last| self.type_of(last)),
+            ASTNode::Call { name, .. } => self.macros.get(name).map_or(Type::Number, |(_, body)| *body),
+            _ => Type::Number,
+        }
+    }
+
+    // Under --strict-bool, reject an expression of the wrong type; `what` describes where it is used
+    fn expect_type(&self, node: &ASTNode, expected: Type, what: &str, span: Span) {
+        let found = self.type_of(node);
+        if self.strict_bool && found != expected {
+            fail(CompileError::TypeMismatch(format!(
+                "{} must be a {}, found a {} at {}",
+                what,
+                expected.name(),
+                found.name(),
+                span
+            )));
+        }
+    }
+
+    // Parse a loop or `if` condition, which must be a comparison under --strict-bool
+    fn parse_condition(&mut self) -> ASTNode {
+        let span = self.current_span;
+        let condition = self.parse_logical();
+        self.expect_type(&condition, Type::Bool, "Condition", span);
+        condition
+    }
+
+    fn eat(&mut self, token: Token) {
+        if self.current_token == token {
+            self.previous_end = self.current_span.end;
+            (self.current_token, self.current_span) = self.lexer.next_significant_token_with_span();
+        } else {
+            fail(CompileError::UnexpectedToken(format!(
+                "Unexpected token: {:?}, expected: {:?} at {}",
+                self.current_token, token, self.current_span
+            )));
+        }
+    }
+
+    fn parse_term(&mut self) -> ASTNode {
+        match self.current_token.clone() {
+            Token::Number(value) => {
+                let span = self.current_span;
+                self.eat(Token::Number(value));
+                let text: String = self.lexer.input[span.start..span.end].iter().collect();
+                if text == value.to_string() {
+                    ASTNode::Number(value)
+                } else {
+                    ASTNode::Literal { value, text }
+                }
+            }
+            Token::Identifier(name) => {
+                let span = self.current_span;
+                self.eat(Token::Identifier(name.clone()));
+                // Check if this is a function call
+                if name == "range" && self.current_token == Token::LParen {
+                    self.eat(Token::LParen);
+                    let arg = self.parse_expression();
+                    self.eat(Token::RParen);
+                    arg  // For now, range(n) just returns n
+                } else if self.current_token == Token::LParen {
+                    self.parse_call(name)
+                } else {
+                    ASTNode::Variable(name, Origin(Some(span)))
+                }
+            }
+            // A parenthesized comparison is an ordinary term worth 1 or 0, e.g. `n + (a < b)`, and
+            // parentheses may also hold assignments and comma-separated sequences
+            Token::LParen => {
+                self.eat(Token::LParen);
+                let mut items = vec![self.parse_sequence_item()];
+                while self.current_token == Token::Comma {
+                    self.eat(Token::Comma);
+                    items.push(self.parse_sequence_item());
+                }
+                self.eat(Token::RParen);
+                if items.len() == 1 {
+                    items.pop().unwrap()
+                } else {
+                    ASTNode::Sequence(items)
+                }
+            }
+            // `= =` lexes as two assignments; only adjacent characters form `==`
+            Token::Assign => fail(CompileError::MisspelledComparison(format!(
+                "Unexpected '=' at {}; the comparison operator is '==' with no space",
+                self.current_span
+            ))),
+            _ => fail(CompileError::UnexpectedToken(format!(
+                "Unexpected token in term: {:?} at {}",
+                self.current_token, self.current_span
+            ))),
+        }
+    }
+
+    fn parse_call(&mut self, name: String) -> ASTNode {
+        let span = self.current_span;
+        let arity = match (self.builtins.get(&name), self.macros.get(&name)) {
+            (Some(builtin), _) => builtin.arity,
+            (None, Some((arity, _))) => *arity,
+            (None, None) => {
+                fail(CompileError::UnknownFunction(format!(
+                    "Unknown function '{}' at {}",
+                    name,
+                    span
+                )))
+            }
+        };
+
+        self.eat(Token::LParen);
+        let mut args = Vec::new();
+        while self.current_token != Token::RParen {
+            if !args.is_empty() {
+                self.eat(Token::Comma);
+            }
+            args.push(self.parse_expression());
+        }
+        self.eat(Token::RParen);
+
+        if args.len() != arity {
+            fail(CompileError::WrongArgumentCount(format!(
+                "Function '{}' expects {} argument(s), got {} at {}",
+                name,
+                arity,
+                args.len(),
+                span
+            )));
+        }
+        ASTNode::Call { name, args }
+    }
+
+    // Arithmetic only: operands and `+ - * / %`, stopping at comparisons and logical operators
+    fn parse_expression(&mut self) -> ASTNode {
+        self.parse_binary(0, true)
+    }
+
+    // Precedence climbing over the operator table: parse a unary operand, then keep folding in
+    // operators that bind at least as tightly as `min_level`. With `arithmetic_only`, stop at
+    // operators other than `+ - * / %`.
+    fn parse_binary(&mut self, min_level: u8, arithmetic_only: bool) -> ASTNode {
+        let left = self.parse_unary();
+        self.parse_binary_rest(left, min_level, arithmetic_only)
+    }
+
+    fn parse_binary_rest(
+        &mut self,
+        mut left: ASTNode,
+        min_level: u8,
+        arithmetic_only: bool,
+    ) -> ASTNode {
+        // Level of the last non-associative operator applied, which may not be followed by
+        // another at the same level
+        let mut unchained = None;
+        loop {
+            if self.current_token == Token::Assign && !arithmetic_only {
+                fail(CompileError::MisspelledComparison(format!(
+                    "Unexpected '=' in comparison at {}; did you mean '=='?",
+                    self.current_span
+                )));
+            }
+            let Some(operator) = Parser::binary_operator(&self.current_token) else {
+                return left;
+            };
+            let Some(&(level, associativity)) = self.precedence.get(operator) else {
+                return left;
+            };
+            if level < min_level
+                || unchained == Some(level)
+                || (arithmetic_only && !Parser::is_arithmetic(operator))
+            {
+                return left;
+            }
+
+            let span = self.current_span;
+            self.eat(self.current_token.clone());
+            let next_level = match associativity {
+                Associativity::Right => level,
+                Associativity::Left | Associativity::None => level + 1,
+            };
+            let right = self.parse_binary(next_level, arithmetic_only);
+            left = self.binary(left, operator, right, span);
+            unchained = (associativity == Associativity::None).then_some(level);
+        }
+    }
+
+    fn binary_operator(token: &Token) -> Option<&'static str> {
+        Some(match token {
+            Token::Or => "||",
+            Token::And => "&&",
+            Token::Equal => "==",
+            Token::NotEqual => "!=",
+            Token::LessThan => "<",
+            Token::GreaterThan => ">",
+            Token::LessEqual => "<=",
+            Token::GreaterEqual => ">=",
+            Token::Plus => "+",
+            Token::Minus => "-",
+            Token::Multiply => "*",
+            Token::Divide => "/",
+            Token::Modulo => "%",
+            _ => return None,
+        })
+    }
+
+    fn is_arithmetic(operator: &str) -> bool {
+        matches!(operator, "+" | "-" | "*" | "/" | "%")
+    }
+
+    // Build a binary operation, applying the operator's type checks
+    fn binary(&self, left: ASTNode, operator: &str, mut right: ASTNode, span: Span) -> ASTNode {
+        match operator {
+            "&&" | "||" => self.logical(left, operator, right, span),
+            _ if Parser::is_arithmetic(operator) => {
+                if self.safe_div && (operator == "/" || operator == "%") {
+                    right = ASTNode::NonZero {
+                        value: Box::new(right),
+                        line: span.line,
+                    };
+                }
+                self.arithmetic(left, operator, right, span)
+            }
+            _ => ASTNode::BinaryOp {
+                left: Box::new(left),
+                operator: operator.to_string(),
+                right: Box::new(right),
+            },
+        }
+    }
+
+    // `-x` is `0 - x` (a negated literal stays a literal) and `!x` is `x == 0`
+    fn parse_unary(&mut self) -> ASTNode {
+        let span = self.current_span;
+        match self.current_token {
+            Token::Minus => {
+                self.eat(Token::Minus);
+                match self.parse_unary() {
+                    ASTNode::Number(value) => ASTNode::Number(-value),
+                    ASTNode::Literal { value, text } => ASTNode::Literal {
+                        value: -value,
+                        text: format!("-{}", text),
+                    },
+                    operand => self.arithmetic(ASTNode::Number(0), "-", operand, span),
+                }
+            }
+            Token::Not => {
+                self.eat(Token::Not);
+                let operand = self.parse_unary();
+                self.expect_type(&operand, Type::Bool, "Operand of '!'", span);
+                ASTNode::BinaryOp {
+                    left: Box::new(operand),
+                    operator: "==".to_string(),
+                    right: Box::new(ASTNode::Number(0)),
+                }
+            }
+            _ => self.parse_term(),
+        }
+    }
+
+    fn arithmetic(&self, left: ASTNode, operator: &str, right: ASTNode, span: Span) -> ASTNode {
+        let what = format!("Operand of '{}'", operator);
+        self.expect_type(&left, Type::Number, &what, span);
+        self.expect_type(&right, Type::Number, &what, span);
+        ASTNode::BinaryOp {
+            left: Box::new(left),
+            operator: operator.to_string(),
+            right: Box::new(right),
+        }
+    }
+
+    // Called with the target name already consumed, since `name:` may turn out to label a loop;
+    // `name_span` is where the name was
+    fn parse_assignment(
+        &mut self,
+        name: String,
+        name_span: Span,
+        storage: Option<Storage>,
+    ) -> ASTNode {
+        self.eat(Token::Assign);
+        let span = self.current_span;
+        let value = self.parse_expression();
+        self.record_type(&name, &value, span);
+        ASTNode::Assignment {
+            variable: name,
+            value: Box::new(value),
+            storage,
+            origin: Origin(Some(name_span)),
+        }
+    }
+
+    // Under --strict-bool, a variable keeps the type of its first assignment
+    fn record_type(&mut self, name: &str, value: &ASTNode, span: Span) {
+        if !self.strict_bool {
+            return;
+        }
+        let found = self.type_of(value);
+        match self.variable_types.get(name) {
+            Some(&declared) if declared != found => fail(CompileError::TypeMismatch(format!(
+                "Variable '{}' holds a {} but is assigned a {} at {}",
+                name,
+                declared.name(),
+                found.name(),
+                span
+            ))),
+            _ => {
+                self.variable_types.insert(name.to_string(), found);
+            }
+        }
+    }
+
+    // `macro name(params) = body`, with `macro` already consumed. The body is a condition, so a
+    // macro may stand for a comparison as well as a number.
+    fn parse_macro(&mut self) -> ASTNode {
+        let span = self.current_span;
+        let Token::Identifier(name) = self.current_token.clone() else {
+            unreachable!("macros are only parsed before an identifier");
+        };
+        if self.builtins.contains_key(&name) || self.macros.contains_key(&name) {
+            fail(CompileError::InvalidMacro(format!(
+                "Macro '{}' is already defined at {}",
+                name,
+                span
+            )));
+        }
+        self.eat(Token::Identifier(name.clone()));
+
+        self.eat(Token::LParen);
+        let mut params: Vec<String> = Vec::new();
+        while self.current_token != Token::RParen {
+            if !params.is_empty() {
+                self.eat(Token::Comma);
+            }
+            let Token::Identifier(param) = self.current_token.clone() else {
+                fail(CompileError::UnexpectedToken(format!(
+                    "Expected a parameter name for macro '{}', found {:?} at {}",
+                    name, self.current_token, self.current_span
+                )));
+            };
+            if params.contains(&param) {
+                fail(CompileError::InvalidMacro(format!(
+                    "Macro '{}' has two parameters named '{}' at {}",
+                    name, param, self.current_span
+                )));
+            }
+            self.eat(Token::Identifier(param.clone()));
+            params.push(param);
+        }
+        self.eat(Token::RParen);
+        self.eat(Token::Assign);
+
+        let body_span = self.current_span;
+        let body = self.parse_logical();
+        // A parameter stands for an expression, which can't be assigned to
+        let mut assigned = Vec::new();
+        ASTNode::collect_variables(&body, &mut assigned);
+        if let Some(param) = params.iter().find(|param| assigned.contains(param)) {
+            fail(CompileError::InvalidMacro(format!(
+                "Macro '{}' assigns to its parameter '{}' at {}",
+                name, param, body_span
+            )));
+        }
+        self.macros.insert(name.clone(), (params.len(), self.type_of(&body)));
+        ASTNode::Macro {
+            name,
+            params,
+            body: Box::new(body),
+        }
+    }
+
+    // The parenthesized arguments of `print` (to stdout) or `eprint` (to stderr): numbers and
+    // strings, printed one after another, optionally followed by a width for a single number
+    fn parse_print(&mut self, fd: u8) -> ASTNode {
+        self.eat(Token::LParen);
+        let mut parts = vec![self.parse_print_argument()];
+        let mut width = None;
+        while self.current_token == Token::Comma {
+            self.eat(Token::Comma);
+            let span = self.current_span;
+            if self.current_token != Token::Identifier("width".to_string()) {
+                parts.push(self.parse_print_argument());
+                continue;
+            }
+            // `width` is only the option when `=` follows; otherwise it names a variable
+            self.eat(Token::Identifier("width".to_string()));
+            if self.current_token != Token::Assign {
+                let variable = match self.current_token {
+                    Token::LParen => self.parse_call("width".to_string()),
+                    _ => ASTNode::Variable("width".to_string(), Origin(Some(span))),
+                };
+                parts.push(FormatPart::Value(self.parse_binary_rest(variable, 0, false)));
+                continue;
+            }
+            if !matches!(parts.as_slice(), [FormatPart::Value(_)]) {
+                fail(CompileError::InvalidPrintWidth(format!(
+                    "Print width needs a single number to print at {}",
+                    span
+                )));
+            }
+            width = Some(self.parse_print_width());
+            break;
+        }
+        self.eat(Token::RParen);
+        ASTNode::Print { parts, width, fd }
+    }
+
+    fn parse_print_argument(&mut self) -> FormatPart {
+        match self.current_token {
+            Token::StringLiteral(_) => FormatPart::Text(self.parse_format()),
+            _ => FormatPart::Value(self.parse_logical()),
+        }
+    }
+
+    // The `=N` of a print's `width=N`. The digits and padding share the 20-byte print buffer,
+    // which holds the longest 64-bit number.
+    fn parse_print_width(&mut self) -> usize {
+        self.eat(Token::Assign);
+        match self.current_token {
+            Token::Number(width) if (1..=20).contains(&width) => {
+                self.eat(Token::Number(width));
+                width as usize
+            }
+            _ => fail(CompileError::InvalidPrintWidth(format!(
+                "Print width must be a whole number from 1 to 20, found {:?} at {}",
+                self.current_token, self.current_span
+            ))),
+        }
+    }
+
+    // `static_assert(condition)`, with `static_assert` already consumed
+    fn parse_static_assert(&mut self, span: Span) -> ASTNode {
+        self.eat(Token::LParen);
+        let condition = self.parse_logical();
+        self.eat(Token::RParen);
+        ASTNode::StaticAssert {
+            condition: Box::new(condition),
+            span,
+        }
+    }
+
+    // `printf("...", args)`, with `printf` already consumed. `%d` prints the next argument and
+    // `%%` a percent sign; the number of `%d`s must match the number of arguments.
+    fn parse_printf(&mut self, span: Span) -> ASTNode {
+        self.eat(Token::LParen);
+        let format = self.parse_format();
+        let mut args = Vec::new();
+        while self.current_token == Token::Comma {
+            self.eat(Token::Comma);
+            args.push(self.parse_expression());
+        }
+        self.eat(Token::RParen);
+
+        // Split the format into text pieces and conversions (None) before pairing up arguments
+        let mut pieces: Vec<Option<String>> = Vec::new();
+        let mut text = String::new();
+        let mut chars = format.chars();
+        while let Some(c) = chars.next() {
+            if c != '%' {
+                text.push(c);
+                continue;
+            }
+            match chars.next() {
+                Some('%') => text.push('%'),
+                Some('d') => {
+                    if !text.is_empty() {
+                        pieces.push(Some(std::mem::take(&mut text)));
+                    }
+                    pieces.push(None);
+                }
+                other => fail(CompileError::InvalidPrintf(format!(
+                    "Unsupported printf conversion '%{}' at {}; only %d and %% are supported",
+                    other.map_or(String::new(), String::from),
+                    span
+                ))),
+            }
+        }
+        if !text.is_empty() {
+            pieces.push(Some(text));
+        }
+
+        let conversions = pieces.iter().filter(|piece| piece.is_none()).count();
+        if conversions != args.len() {
+            fail(CompileError::InvalidPrintf(format!(
+                "printf format has {} '%d' conversion(s) but {} argument(s) at {}",
+                conversions,
+                args.len(),
+                span
+            )));
+        }
+        let mut args = args.into_iter();
+        let parts = pieces
+            .into_iter()
+            .map(|piece| match piece {
+                Some(text) => FormatPart::Text(text),
+                None => FormatPart::Value(args.next().unwrap()),
+            })
+            .collect();
+        ASTNode::Printf { parts }
+    }
+
+    // A string literal, or literals joined with `+`, which must fold to a single literal
+    fn parse_format(&mut self) -> String {
+        let mut format = self.parse_string_literal();
+        while self.current_token == Token::Plus {
+            self.eat(Token::Plus);
+            format = ASTNode::BinaryOp {
+                left: Box::new(format),
+                operator: "+".to_string(),
+                right: Box::new(self.parse_string_literal()),
+            };
+        }
+        match Optimizer::fold(format, &HashMap::new()) {
+            ASTNode::StringLiteral(text) => text,
+            other => unreachable!("string literals always fold, got {:?}", other),
+        }
+    }
+
+    fn parse_string_literal(&mut self) -> ASTNode {
+        let Token::StringLiteral(text) = self.current_token.clone() else {
+            fail(CompileError::UnexpectedToken(format!(
+                "Expected a format string for 'printf', found {:?} at {}",
+                self.current_token, self.current_span
+            )));
+        };
+        self.eat(Token::StringLiteral(text.clone()));
+        ASTNode::StringLiteral(text)
+    }
+
+    // `a, b = 1, 2`, with the first target already consumed
+    fn parse_multi_assign(&mut self, first: String, span: Span) -> ASTNode {
+        let mut targets = vec![first];
+        let mut origins = vec![Origin(Some(span))];
+        while self.current_token == Token::Comma {
+            self.eat(Token::Comma);
+            let Token::Identifier(name) = self.current_token.clone() else {
+                fail(CompileError::UnexpectedToken(format!(
+                    "Expected a variable to assign to, found {:?} at {}",
+                    self.current_token, self.current_span
+                )));
+            };
+            if targets.contains(&name) {
+                fail(CompileError::InvalidMultipleAssignment(format!(
+                    "Variable '{}' is assigned twice in one multiple assignment at {}",
+                    name, self.current_span
+                )));
+            }
+            origins.push(Origin(Some(self.current_span)));
+            self.eat(Token::Identifier(name.clone()));
+            targets.push(name);
+        }
+        self.eat(Token::Assign);
+
+        let mut values = Vec::new();
+        let mut value_spans = Vec::new();
+        loop {
+            value_spans.push(self.current_span);
+            values.push(self.parse_expression());
+            if self.current_token != Token::Comma {
+                break;
+            }
+            self.eat(Token::Comma);
+        }
+        if values.len() != targets.len() {
+            fail(CompileError::InvalidMultipleAssignment(format!(
+                "Multiple assignment has {} target(s) but {} value(s) at {}",
+                targets.len(),
+                values.len(),
+                span
+            )));
+        }
+        for ((target, value), value_span) in targets.iter().zip(&values).zip(value_spans) {
+            self.record_type(target, value, value_span);
+        }
+        ASTNode::MultiAssign {
+            targets,
+            values,
+            origins,
+        }
+    }
+
+    // `global x = ...` / `local x = ...`, with the hint word already consumed. A variable's
+    // hints must agree, since each variable has a single slot.
+    fn parse_storage_hint(&mut self, storage: Storage, span: Span) -> ASTNode {
+        let Token::Identifier(name) = self.current_token.clone() else {
+            unreachable!("storage hints are only parsed before an identifier");
+        };
+        match self.storage_hints.get(&name) {
+            Some((previous, previous_span)) if *previous != storage => {
+                fail(CompileError::ConflictingStorage(format!(
+                    "Variable '{}' is declared {} at {} but {} at {}",
+                    name,
+                    storage.keyword(),
+                    span,
+                    previous.keyword(),
+                    previous_span
+                )))
+            }
+            _ => {
+                self.storage_hints.insert(name.clone(), (storage, span));
+            }
+        }
+        let name_span = self.current_span;
+        self.eat(Token::Identifier(name.clone()));
+        self.parse_assignment(name, name_span, Some(storage))
+    }
+
+    // A comparison, or an assignment whose value is the assigned value
+    fn parse_sequence_item(&mut self) -> ASTNode {
+        let span = self.current_span;
+        let left = self.parse_expression();
+        match left {
+            ASTNode::Variable(name, Origin(Some(name_span)))
+                if self.current_token == Token::Assign =>
+            {
+                self.parse_assignment(name, name_span, None)
+            }
+            left => {
+                self.check_assign_target(&left, span);
+                self.parse_binary_rest(left, 0, false)
+            }
+        }
+    }
+
+    // Only a variable can be assigned, so an `=` after any other expression (`5 = x`,
+    // `(a + 1) = 5`) is rejected here rather than as an unexpected token
+    fn check_assign_target(&self, target: &ASTNode, span: Span) {
+        if self.current_token == Token::Assign {
+            fail(CompileError::InvalidAssignmentTarget(format!(
+                "Cannot assign to '{}' at {}; only a variable can be assigned",
+                Formatter::expression(target),
+                span
+            )));
+        }
+    }
+
+    // A condition: any expression, including comparisons and `&&`/`||`
+    fn parse_logical(&mut self) -> ASTNode {
+        self.parse_binary(0, false)
+    }
+
+    fn logical(&self, left: ASTNode, operator: &str, right: ASTNode, span: Span) -> ASTNode {
+        let what = format!("Operand of '{}'", operator);
+        self.expect_type(&left, Type::Bool, &what, span);
+        self.expect_type(&right, Type::Bool, &what, span);
+        ASTNode::BinaryOp {
+            left: Box::new(left),
+            operator: operator.to_string(),
+            right: Box::new(right),
+        }
+    }
+
+    fn parse_if(&mut self) -> ASTNode {
+        self.eat(Token::If);
+        // Parentheses around the condition are optional: `(a < b)` is itself a parenthesized
+        // term, so `if (a < b) {` and `if a < b {` parse to the same condition
+        let condition = self.parse_condition();
+        self.eat(Token::LBrace);
+        let then_branch = self.parse_block();
+        self.eat(Token::RBrace);
+
+        let else_branch = if self.current_token == Token::Else {
+            self.eat(Token::Else);
+            self.eat(Token::LBrace);
+            let branch = self.parse_block();
+            self.eat(Token::RBrace);
+            branch
+        } else {
+            vec![]
+        };
+
+        ASTNode::If {
+            condition: Box::new(condition),
+            then_branch,
+            else_branch,
+        }
+    }
+    fn parse_while(&mut self, label: Option<String>) -> ASTNode {
+        self.eat(Token::While);
+        // As in `if`, the condition's parentheses are optional
+        let condition = self.parse_condition();
+        self.eat(Token::LBrace);
+        let body = self.parse_loop_body(&label);
+        self.eat(Token::RBrace);
+
+        ASTNode::While {
+            label,
+            condition: Box::new(condition),
+            body,
+        }
+    }
+    fn parse_do_while(&mut self, label: Option<String>) -> ASTNode {
+        self.eat(Token::Do);
+        self.eat(Token::LBrace);
+        let body = self.parse_loop_body(&label);
+        self.eat(Token::RBrace);
+        self.eat(Token::While);
+        self.eat(Token::LParen);
+        let condition = self.parse_condition();
+        self.eat(Token::RParen);
+
+        ASTNode::DoWhile {
+            label,
+            body,
+            condition: Box::new(condition),
+        }
+    }
+    fn parse_for(&mut self, label: Option<String>) -> ASTNode {
+        self.eat(Token::For);
+        self.eat(Token::LParen);
+        if let Token::Identifier(name) = self.current_token.clone() {
+            let origin = Origin(Some(self.current_span));
+            self.eat(Token::Identifier(name.clone()));
+            self.eat(Token::In);
+            let range_expr = self.parse_expression();
+            self.eat(Token::RParen);
+            self.eat(Token::LBrace);
+            let body = self.parse_loop_body(&label);
+            self.eat(Token::RBrace);
+            ASTNode::For {
+                label,
+                variable: name,
+                range_expr: Box::new(range_expr),
+                body,
+                origin,
+            }
+        } else {
+            fail(CompileError::UnexpectedToken(format!(
+                "Expected an identifier for 'for' loop variable at {}",
+                self.current_span
+            )));
+        }
+    }
+    fn parse_match(&mut self) -> ASTNode {
+        self.eat(Token::Match);
+        let scrutinee = self.parse_expression();
+        self.eat(Token::LBrace);
+
+        let mut arms: Vec<(i64, Vec<ASTNode>)> = Vec::new();
+        let mut default = Vec::new();
+        while self.current_token != Token::RBrace {
+            match self.current_token.clone() {
+                Token::Number(value) => {
+                    let span = self.current_span;
+                    if arms.iter().any(|(existing, _)| *existing == value) {
+                        fail(CompileError::DuplicateMatchArm(format!(
+                            "Duplicate match arm {} at {}",
+                            value,
+                            span
+                        )));
+                    }
+                    self.eat(Token::Number(value));
+                    self.eat(Token::LBrace);
+                    let body = self.parse_block();
+                    self.eat(Token::RBrace);
+                    arms.push((value, body));
+                }
+                Token::Else => {
+                    // The default arm must come last
+                    self.eat(Token::Else);
+                    self.eat(Token::LBrace);
+                    default = self.parse_block();
+                    self.eat(Token::RBrace);
+                    break;
+                }
+                _ => fail(CompileError::UnexpectedToken(format!(
+                    "Expected an integer match arm or 'else', found {:?} at {}",
+                    self.current_token, self.current_span
+                ))),
+            }
+        }
+        self.eat(Token::RBrace);
+
+        ASTNode::Match {
+            scrutinee: Box::new(scrutinee),
+            arms,
+            default,
+        }
+    }
+    fn parse_loop_body(&mut self, label: &Option<String>) -> Vec<ASTNode> {
+        self.loop_labels.push(label.clone());
+        let body = self.parse_block();
+        self.loop_labels.pop();
+        body
+    }
+
+    // `name: while ...`; the label has already been consumed
+    fn parse_labeled_loop(&mut self, label: String, span: Span) -> ASTNode {
+        if self.loop_labels.contains(&Some(label.clone())) {
+            fail(CompileError::MisplacedLoopControl(format!(
+                "Loop label '{}' shadows an enclosing loop at {}",
+                label,
+                span
+            )));
+        }
+        match self.current_token {
+            Token::While => self.parse_while(Some(label)),
+            Token::Do => self.parse_do_while(Some(label)),
+            Token::For => self.parse_for(Some(label)),
+            _ => fail(CompileError::UnexpectedToken(format!(
+                "Expected a loop after label '{}', found {:?} at {}",
+                label, self.current_token, self.current_span
+            ))),
+        }
+    }
+
+    // Parse the optional label after `break`/`continue` and check it against the enclosing loops.
+    // The label must sit on the same line, so a following statement is never mistaken for one.
+    fn parse_jump_label(&mut self, keyword: &str, span: Span) -> Option<String> {
+        if self.loop_labels.is_empty() {
+            fail(CompileError::MisplacedLoopControl(format!(
+                "'{}' outside of a loop at {}",
+                keyword,
+                span
+            )));
+        }
+        match self.current_token.clone() {
+            Token::Identifier(name) if self.current_span.line == span.line => {
+                if !self.loop_labels.contains(&Some(name.clone())) {
+                    fail(CompileError::MisplacedLoopControl(format!(
+                        "Unknown loop label '{}' for '{}' at {}",
+                        name, keyword, self.current_span
+                    )));
+                }
+                self.eat(Token::Identifier(name.clone()));
+                Some(name)
+            }
+            _ => None,
+        }
+    }
+
+    fn parse_block(&mut self) -> Vec<ASTNode> {
+        self.depth += 1;
+        let mut statements = Vec::new();
+        while self.current_token != Token::RBrace && self.current_token != Token::EOF {
+            let span = self.current_span;
+            let statement = self.parse_statement();
+            if matches!(statements.last(), Some(ASTNode::Break(_) | ASTNode::Continue(_))) {
+                self.unreachable_spans.push(span);
+            }
+            statements.push(statement);
+        }
+        self.depth -= 1;
+        statements
+    }
+
+    // `defer statement`, with `defer` already consumed
+    fn parse_defer(&mut self, span: Span) -> ASTNode {
+        if self.depth > 0 {
+            fail(CompileError::MisplacedDefer(format!(
+                "'defer' is only allowed at the top level, at {}",
+                span
+            )));
+        }
+        self.depth += 1;
+        let statement = self.parse_statement();
+        self.depth -= 1;
+        ASTNode::Defer(Box::new(statement))
+    }
+
+    fn parse_statement(&mut self) -> ASTNode {
+        match self.current_token.clone() {
+            Token::If => self.parse_if(),
+            Token::While => self.parse_while(None),
+            Token::Do => self.parse_do_while(None),
+            Token::For => self.parse_for(None),
+            Token::Match => self.parse_match(),
+            Token::Print => {
+                self.eat(Token::Print);
+                self.parse_print(1)
+            }
+            Token::Identifier(name) => {
+                let span = self.current_span;
+                self.eat(Token::Identifier(name.clone()));
+                // `global`/`local` are only hints when another name follows, so they stay usable
+                // as variable names
+                let hint = match name.as_str() {
+                    "global" => Some(Storage::Global),
+                    "local" => Some(Storage::Local),
+                    _ => None,
+                };
+                match hint {
+                    Some(storage) if matches!(self.current_token, Token::Identifier(_)) => {
+                        self.parse_storage_hint(storage, span)
+                    }
+                    // Like the hints, `printf` is only special when it is called
+                    _ if name == "printf" && self.current_token == Token::LParen => {
+                        self.parse_printf(span)
+                    }
+                    _ if name == "eprint" && self.current_token == Token::LParen => {
+                        self.parse_print(2)
+                    }
+                    _ if name == "static_assert" && self.current_token == Token::LParen => {
+                        self.parse_static_assert(span)
+                    }
+                    _ if name == "macro" && matches!(self.current_token, Token::Identifier(_)) => {
+                        self.parse_macro()
+                    }
+                    // A statement never starts with these, so `defer = 1` still assigns
+                    _ if name == "defer"
+                        && !matches!(
+                            self.current_token,
+                            Token::Assign | Token::Colon | Token::Comma | Token::EOF
+                        ) =>
+                    {
+                        self.parse_defer(span)
+                    }
+                    _ if self.current_token == Token::Colon => {
+                        self.eat(Token::Colon);
+                        self.parse_labeled_loop(name, span)
+                    }
+                    _ if self.current_token == Token::Comma => self.parse_multi_assign(name, span),
+                    // `a + 1 = 5`: parse the whole left side to report it as the target
+                    _ if Parser::binary_operator(&self.current_token).is_some() => {
+                        let variable = ASTNode::Variable(name, Origin(Some(span)));
+                        let target = self.parse_binary_rest(variable, 0, true);
+                        self.check_assign_target(&target, span);
+                        self.eat(Token::Assign);
+                        unreachable!("an '=' after an expression is E020")
+                    }
+                    _ => self.parse_assignment(name, span, None),
+                }
+            }
+            Token::Break => {
+                let span = self.current_span;
+                self.eat(Token::Break);
+                ASTNode::Break(self.parse_jump_label("break", span))
+            }
+            Token::Continue => {
+                let span = self.current_span;
+                self.eat(Token::Continue);
+                ASTNode::Continue(self.parse_jump_label("continue", span))
+            }
+            // No statement starts with a number or parenthesis, but `5 = x` deserves a better
+            // error than one
+            Token::Number(_) | Token::LParen => {
+                let (token, span) = (self.current_token.clone(), self.current_span);
+                let target = self.parse_expression();
+                self.check_assign_target(&target, span);
+                fail(CompileError::UnexpectedToken(format!(
+                    "Unexpected token: {:?} at {}. Expected a statement.",
+                    token,
+                    span
+                )))
+            }
+            _ => fail(CompileError::UnexpectedToken(format!(
+                "Unexpected token: {:?} at {}. Expected a statement.",
+                self.current_token, self.current_span
+            ))),
+        }
+    }
+
+    fn parse(&mut self) -> Vec<ASTNode> {
+        let mut nodes = Vec::new();
+        while self.current_token != Token::EOF {
+            let start = self.current_span;
+            nodes.push(self.parse_statement());
+            self.statement_spans.push(Span {
+                end: self.previous_end,
+                ..start
+            });
+        }
+        nodes
+    }
+}
+
+// AST-to-AST optimization passes, enabled with -O
+// Under -O, the most iterations a `for` loop over a constant range may have to be unrolled
+const DEFAULT_MAX_UNROLL: usize = 8;
+
+struct Optimizer {
+    temp_counter: usize,
+    // `for` loops over a constant range of at most this many iterations are unrolled
+    max_unroll: usize,
+}
+
+impl Optimizer {
+    fn new() -> Self {
+        Optimizer {
+            temp_counter: 0,
+            max_unroll: DEFAULT_MAX_UNROLL,
+        }
+    }
+
+    // 0 disables unrolling
+    fn with_max_unroll(mut self, max_unroll: usize) -> Self {
+        self.max_unroll = max_unroll;
+        self
+    }
+
+    fn optimize(&mut self, nodes: Vec<ASTNode>) -> Vec<ASTNode> {
+        let nodes = Optimizer::propagate_constants(nodes, &mut HashMap::new());
+        // Propagate again so each unrolled copy sees its value of the loop variable
+        let nodes = self.unroll_loops(nodes);
+        let nodes = Optimizer::propagate_constants(nodes, &mut HashMap::new());
+        self.eliminate_common_subexpressions(nodes)
+    }
+
+    // Replace `for (i in range(N))` with N copies of its body, each after `i = k`, when N is a
+    // constant no larger than max_unroll. The body must not jump (a `break` or `continue` in a
+    // copy has no loop left to leave) or assign the loop variable, which would change the next
+    // iteration's value. The variable ends up holding N - 1, as after the loop.
+    fn unroll_loops(&self, nodes: Vec<ASTNode>) -> Vec<ASTNode> {
+        let mut result = Vec::new();
+        for node in nodes {
+            match node {
+                ASTNode::For {
+                    label,
+                    variable,
+                    range_expr,
+                    body,
+                    origin,
+                } => {
+                    let body = self.unroll_loops(body);
+                    let mut assigned = Vec::new();
+                    for stmt in &body {
+                        ASTNode::collect_variables(stmt, &mut assigned);
+                    }
+                    let trips = range_expr
+                        .constant()
+                        .map(|count| count.max(0) as usize)
+                        .filter(|trips| *trips <= self.max_unroll);
+                    match trips {
+                        Some(trips) if !Optimizer::jumps(&body) && !assigned.contains(&variable) => {
+                            for trip in 0..trips {
+                                result.push(ASTNode::Assignment {
+                                    variable: variable.clone(),
+                                    value: Box::new(ASTNode::Number(trip as i64)),
+                                    storage: None,
+                                    origin,
+                                });
+                                result.extend(body.iter().cloned());
+                            }
+                        }
+                        _ => result.push(ASTNode::For {
+                            label,
+                            variable,
+                            range_expr,
+                            body,
+                            origin,
+                        }),
+                    }
+                }
+                ASTNode::If {
+                    condition,
+                    then_branch,
+                    else_branch,
+                } => result.push(ASTNode::If {
+                    condition,
+                    then_branch: self.unroll_loops(then_branch),
+                    else_branch: self.unroll_loops(else_branch),
+                }),
+                ASTNode::While { label, condition, body } => result.push(ASTNode::While {
+                    label,
+                    condition,
+                    body: self.unroll_loops(body),
+                }),
+                ASTNode::DoWhile { label, body, condition } => result.push(ASTNode::DoWhile {
+                    label,
+                    body: self.unroll_loops(body),
+                    condition,
+                }),
+                ASTNode::Match {
+                    scrutinee,
+                    arms,
+                    default,
+                } => result.push(ASTNode::Match {
+                    scrutinee,
+                    arms: arms
+                        .into_iter()
+                        .map(|(value, body)| (value, self.unroll_loops(body)))
+                        .collect(),
+                    default: self.unroll_loops(default),
+                }),
+                other => result.push(other),
+            }
+        }
+        result
+    }
+
+    // Whether any statement in the body is a `break` or `continue`, at any depth
+    fn jumps(body: &[ASTNode]) -> bool {
+        body.iter().any(|stmt| match stmt {
+            ASTNode::Break(_) | ASTNode::Continue(_) => true,
+            ASTNode::If {
+                then_branch,
+                else_branch,
+                ..
+            } => Optimizer::jumps(then_branch) || Optimizer::jumps(else_branch),
+            ASTNode::Match { arms, default, .. } => {
+                arms.iter().any(|(_, arm)| Optimizer::jumps(arm)) || Optimizer::jumps(default)
+            }
+            ASTNode::While { body, .. }
+            | ASTNode::DoWhile { body, .. }
+            | ASTNode::For { body, .. } => Optimizer::jumps(body),
+            _ => false,
+        })
+    }
+
+    // Track variables holding a known constant through straight-line code, substitute them into
+    // later expressions and fold the result. A variable assigned inside a loop or a conditional
+    // branch is forgotten, since its value afterwards depends on control flow.
+    fn propagate_constants(nodes: Vec<ASTNode>, known: &mut HashMap<String, i64>) -> Vec<ASTNode> {
+        nodes
+            .into_iter()
+            .map(|node| Optimizer::propagate_statement(node, known))
+            .collect()
+    }
+
+    fn propagate_statement(node: ASTNode, known: &mut HashMap<String, i64>) -> ASTNode {
+        // Assignments nested in the statement's expressions (`print((a = 2, a))`) make their
+        // targets unknown before anything is folded
+        let nested = match &node {
+            ASTNode::Assignment { value: expr, .. }
+            | ASTNode::If { condition: expr, .. }
+            | ASTNode::While { condition: expr, .. }
+            | ASTNode::DoWhile { condition: expr, .. }
+            | ASTNode::For { range_expr: expr, .. }
+            | ASTNode::Match { scrutinee: expr, .. } => vec![&**expr],
+            ASTNode::MultiAssign { values, .. } => values.iter().collect(),
+            ASTNode::Print { parts, .. } | ASTNode::Printf { parts } => {
+                ASTNode::format_values(parts).collect()
+            }
+            _ => Vec::new(),
+        };
+        Optimizer::forget_assigned(nested.into_iter(), known);
+
+        match node {
+            ASTNode::Assignment {
+                variable,
+                value,
+                storage,
+                origin,
+            } => {
+                let value = Optimizer::fold(*value, known);
+                if let Some(constant) = value.constant() {
+                    known.insert(variable.clone(), constant);
+                } else {
+                    known.remove(&variable);
+                }
+                ASTNode::Assignment {
+                    variable,
+                    value: Box::new(value),
+                    storage,
+                    origin,
+                }
+            }
+            ASTNode::StaticAssert { condition, span } => {
+                match Optimizer::fold((*condition).clone(), known).constant() {
+                    Some(value) if value != 0 => {}
+                    Some(_) => fail(CompileError::StaticAssertFailed(format!(
+                        "Static assertion failed: {} at {}",
+                        Formatter::expression(&condition),
+                        span
+                    ))),
+                    None => fail(CompileError::StaticAssertNotConstant(format!(
+                        "Static assertion is not a compile-time constant: {} at {}",
+                        Formatter::expression(&condition),
+                        span
+                    ))),
+                }
+                ASTNode::StaticAssert { condition, span }
+            }
+            ASTNode::MultiAssign { targets, values, origins } => {
+                // All values see the variables as they were before the statement
+                let values: Vec<ASTNode> = values
+                    .into_iter()
+                    .map(|value| Optimizer::fold(value, known))
+                    .collect();
+                for (target, value) in targets.iter().zip(&values) {
+                    match value.constant() {
+                        Some(constant) => known.insert(target.clone(), constant),
+                        None => known.remove(target),
+                    };
+                }
+                ASTNode::MultiAssign { targets, values, origins }
+            }
+            ASTNode::Print { parts, width, fd } => ASTNode::Print {
+                parts: Optimizer::fold_parts(parts, known),
+                width,
+                fd,
+            },
+            ASTNode::Printf { parts } => ASTNode::Printf {
+                parts: Optimizer::fold_parts(parts, known),
+            },
+            ASTNode::If {
+                condition,
+                then_branch,
+                else_branch,
+            } => {
+                let condition = Optimizer::fold(*condition, known);
+                let then_branch = Optimizer::propagate_constants(then_branch, &mut known.clone());
+                let else_branch = Optimizer::propagate_constants(else_branch, &mut known.clone());
+                Optimizer::forget_assigned(then_branch.iter().chain(else_branch.iter()), known);
+                ASTNode::If {
+                    condition: Box::new(condition),
+                    then_branch,
+                    else_branch,
+                }
+            }
+            ASTNode::While { label, condition, body } => {
+                Optimizer::forget_assigned(body.iter(), known);
+                ASTNode::While {
+                    label,
+                    condition: Box::new(Optimizer::fold(*condition, known)),
+                    body: Optimizer::propagate_constants(body, &mut known.clone()),
+                }
+            }
+            ASTNode::DoWhile { label, body, condition } => {
+                Optimizer::forget_assigned(body.iter(), known);
+                ASTNode::DoWhile {
+                    label,
+                    body: Optimizer::propagate_constants(body, &mut known.clone()),
+                    condition: Box::new(Optimizer::fold(*condition, known)),
+                }
+            }
+            ASTNode::For {
+                label,
+                variable,
+                range_expr,
+                body,
+                origin,
+            } => {
+                let range_expr = Optimizer::fold(*range_expr, known);
+                known.remove(&variable);
+                Optimizer::forget_assigned(body.iter(), known);
+                ASTNode::For {
+                    label,
+                    variable,
+                    range_expr: Box::new(range_expr),
+                    body: Optimizer::propagate_constants(body, &mut known.clone()),
+                    origin,
+                }
+            }
+            ASTNode::Match {
+                scrutinee,
+                arms,
+                default,
+            } => {
+                let scrutinee = Optimizer::fold(*scrutinee, known);
+                let arms: Vec<(i64, Vec<ASTNode>)> = arms
+                    .into_iter()
+                    .map(|(value, body)| {
+                        (value, Optimizer::propagate_constants(body, &mut known.clone()))
+                    })
+                    .collect();
+                let default = Optimizer::propagate_constants(default, &mut known.clone());
+                Optimizer::forget_assigned(
+                    arms.iter().flat_map(|(_, body)| body).chain(default.iter()),
+                    known,
+                );
+                ASTNode::Match {
+                    scrutinee: Box::new(scrutinee),
+                    arms,
+                    default,
+                }
+            }
+            other => other,
+        }
+    }
+
+    fn fold_parts(parts: Vec<FormatPart>, known: &HashMap<String, i64>) -> Vec<FormatPart> {
+        parts
+            .into_iter()
+            .map(|part| match part {
+                FormatPart::Value(value) => FormatPart::Value(Optimizer::fold(value, known)),
+                text => text,
+            })
+            .collect()
+    }
+
+    // static_assert conditions are checked as constant propagation reaches them, so it runs over
+    // the program even without -O. `known` carries the constants from earlier inputs.
+    fn check_static_asserts(nodes: &[ASTNode], known: &mut HashMap<String, i64>) {
+        Optimizer::propagate_constants(nodes.to_vec(), known);
+    }
+
+    fn forget_assigned<'a>(
+        statements: impl Iterator<Item = &'a ASTNode>,
+        known: &mut HashMap<String, i64>,
+    ) {
+        let mut assigned = Vec::new();
+        for stmt in statements {
+            ASTNode::collect_variables(stmt, &mut assigned);
+        }
+        for variable in assigned {
+            known.remove(&variable);
+        }
+    }
+
+    // Substitute known variables and evaluate operators whose operands are both constant. A
+    // compound expression that becomes a constant is kept as Folded, remembering its source.
+    fn fold(expr: ASTNode, known: &HashMap<String, i64>) -> ASTNode {
+        let original = match expr {
+            ASTNode::Number(_) | ASTNode::Literal { .. } | ASTNode::Folded { .. } => None,
+            _ => Some(expr.clone()),
+        };
+        match (Optimizer::fold_constants(expr, known), original) {
+            (ASTNode::Number(value), Some(original)) => ASTNode::Folded {
+                value,
+                source: Formatter::expression(&original),
+            },
+            (folded, _) => folded,
+        }
+    }
+
+    fn fold_constants(expr: ASTNode, known: &HashMap<String, i64>) -> ASTNode {
+        match expr {
+            ASTNode::Literal { value, .. } => ASTNode::Number(value),
+            ASTNode::Variable(name, origin) => match known.get(&name) {
+                Some(value) => ASTNode::Number(*value),
+                None => ASTNode::Variable(name, origin),
+            },
+            ASTNode::BinaryOp {
+                left,
+                operator,
+                right,
+            } => {
+                let left = Optimizer::fold(*left, known);
+                let right = Optimizer::fold(*right, known);
+                if let (Some(a), Some(b)) = (left.constant(), right.constant()) {
+                    if let Some(value) = Optimizer::fold_binary(&operator, a, b) {
+                        return ASTNode::Number(value);
+                    }
+                }
+                if let (ASTNode::StringLiteral(a), ASTNode::StringLiteral(b)) = (&left, &right) {
+                    if operator == "+" {
+                        return ASTNode::StringLiteral(format!("{}{}", a, b));
+                    }
+                }
+                Optimizer::simplify_boolean(left, operator, right)
+            }
+            ASTNode::Call { name, args } => ASTNode::Call {
+                name,
+                args: args
+                    .into_iter()
+                    .map(|arg| Optimizer::fold(arg, known))
+                    .collect(),
+            },
+            ASTNode::Assignment {
+                variable,
+                value,
+                storage,
+                origin,
+            } => ASTNode::Assignment {
+                variable,
+                value: Box::new(Optimizer::fold(*value, known)),
+                storage,
+                origin,
+            },
+            ASTNode::Sequence(items) => ASTNode::Sequence(
+                items
+                    .into_iter()
+                    .map(|item| Optimizer::fold(item, known))
+                    .collect(),
+            ),
+            // A divisor that folds to a nonzero constant needs no check
+            ASTNode::NonZero { value, line } => match Optimizer::fold(*value, known) {
+                value if value.constant().is_some_and(|value| value != 0) => value,
+                value => ASTNode::NonZero {
+                    value: Box::new(value),
+                    line,
+                },
+            },
+            other => other,
+        }
+    }
+
+    // Boolean identities with one constant operand, under the model that any nonzero value is
+    // true and comparisons, `&&`, `||` and `!` produce 0 or 1. `!!x` (`(x == 0) == 0`), `x && 1`
+    // and `x || 0` are x as a 0/1 value. `x && 0` and `x || 1` are constants, but x still runs
+    // unless it's pure. When the constant comes first, x never runs or decides alone.
+    fn simplify_boolean(left: ASTNode, operator: String, right: ASTNode) -> ASTNode {
+        fn constant(node: &ASTNode) -> Option<bool> {
+            node.constant().map(|value| value != 0)
+        }
+        // x as 0 or 1, which comparisons and logical operators already are
+        fn truth(x: ASTNode) -> ASTNode {
+            match &x {
+                ASTNode::BinaryOp { operator, .. }
+                    if matches!(
+                        operator.as_str(),
+                        "==" | "!=" | "<" | ">" | "<=" | ">=" | "&&" | "||"
+                    ) =>
+                {
+                    x
+                }
+                _ => ASTNode::BinaryOp {
+                    left: Box::new(x),
+                    operator: "!=".to_string(),
+                    right: Box::new(ASTNode::Number(0)),
+                },
+            }
+        }
+        // The constant, after evaluating x for its side effects
+        fn after(x: ASTNode, value: i64) -> ASTNode {
+            if Optimizer::is_pure(&x) {
+                ASTNode::Number(value)
+            } else {
+                ASTNode::Sequence(vec![x, ASTNode::Number(value)])
+            }
+        }
+
+        match (operator.as_str(), constant(&left), constant(&right)) {
+            ("==", None, Some(false)) => match left {
+                ASTNode::BinaryOp {
+                    left: inner,
+                    operator: inner_operator,
+                    right: zero,
+                } if inner_operator == "==" && constant(&zero) == Some(false) => truth(*inner),
+                left => ASTNode::BinaryOp {
+                    left: Box::new(left),
+                    operator,
+                    right: Box::new(right),
+                },
+            },
+            ("&&", None, Some(true)) | ("||", None, Some(false)) => truth(left),
+            ("&&", Some(true), None) | ("||", Some(false), None) => truth(right),
+            ("&&", None, Some(false)) => after(left, 0),
+            ("||", None, Some(true)) => after(left, 1),
+            ("&&", Some(false), None) => ASTNode::Number(0),
+            ("||", Some(true), None) => ASTNode::Number(1),
+            _ => ASTNode::BinaryOp {
+                left: Box::new(left),
+                operator,
+                right: Box::new(right),
+            },
+        }
+    }
+
+    // Evaluate with the same semantics as the generated code: wrapping 64-bit arithmetic,
+    // signed truncating division and signed comparisons. A division that traps at runtime (by
+    // zero, or i64::MIN by -1) is left for runtime.
+    fn fold_binary(operator: &str, a: i64, b: i64) -> Option<i64> {
+        let value = match operator {
+            "+" => a.wrapping_add(b),
+            "-" => a.wrapping_sub(b),
+            "*" => a.wrapping_mul(b),
+            "/" => a.checked_div(b)?,
+            "%" => a.checked_rem(b)?,
+            "==" => (a == b) as i64,
+            "!=" => (a != b) as i64,
+            "<" => (a < b) as i64,
+            ">" => (a > b) as i64,
+            "<=" => (a <= b) as i64,
+            ">=" => (a >= b) as i64,
+            "&&" => (a != 0 && b != 0) as i64,
+            "||" => (a != 0 || b != 0) as i64,
+            _ => return None,
+        };
+        Some(value)
+    }
+
+    // Lint: collect the loops (by keyword) whose condition folds to a non-zero constant and whose
+    // body has no `break` leaving them. Such a loop never terminates.
+    fn infinite_loops(nodes: &[ASTNode], found: &mut Vec<&'static str>) {
+        for node in nodes {
+            let (keyword, condition, body) = match node {
+                ASTNode::While { condition, body, .. } => ("while", Some(condition), body),
+                ASTNode::DoWhile { body, condition, .. } => ("do-while", Some(condition), body),
+                ASTNode::For { body, .. } => ("for", None, body),
+                ASTNode::If {
+                    then_branch,
+                    else_branch,
+                    ..
+                } => {
+                    Optimizer::infinite_loops(then_branch, found);
+                    Optimizer::infinite_loops(else_branch, found);
+                    continue;
+                }
+                ASTNode::Match { arms, default, .. } => {
+                    for (_, body) in arms {
+                        Optimizer::infinite_loops(body, found);
+                    }
+                    Optimizer::infinite_loops(default, found);
+                    continue;
+                }
+                _ => continue,
+            };
+            let always_true = condition.is_some_and(|condition| {
+                Optimizer::fold((**condition).clone(), &HashMap::new())
+                    .constant()
+                    .is_some_and(|value| value != 0)
+            });
+            if always_true && !Optimizer::breaks_out(body, &mut Vec::new()) {
+                found.push(keyword);
+            }
+            Optimizer::infinite_loops(body, found);
+        }
+    }
+
+    // Drop the statements that follow a `break` or `continue` in the same block: control never
+    // reaches them. `removed` counts the dropped statements.
+    fn remove_unreachable(node: ASTNode, removed: &mut usize) -> ASTNode {
+        let mut block = |body: Vec<ASTNode>| {
+            let mut reachable = Vec::new();
+            let mut statements = body.into_iter();
+            for stmt in statements.by_ref() {
+                let jumps = matches!(stmt, ASTNode::Break(_) | ASTNode::Continue(_));
+                reachable.push(Optimizer::remove_unreachable(stmt, removed));
+                if jumps {
+                    break;
+                }
+            }
+            *removed += statements.count();
+            reachable
+        };
+        match node {
+            ASTNode::If {
+                condition,
+                then_branch,
+                else_branch,
+            } => ASTNode::If {
+                condition,
+                then_branch: block(then_branch),
+                else_branch: block(else_branch),
+            },
+            ASTNode::While { label, condition, body } => ASTNode::While {
+                label,
+                condition,
+                body: block(body),
+            },
+            ASTNode::DoWhile { label, body, condition } => ASTNode::DoWhile {
+                label,
+                body: block(body),
+                condition,
+            },
+            ASTNode::For {
+                label,
+                variable,
+                range_expr,
+                body,
+                origin,
+            } => ASTNode::For {
+                label,
+                variable,
+                range_expr,
+                body: block(body),
+                origin,
+            },
+            ASTNode::Match {
+                scrutinee,
+                arms,
+                default,
+            } => ASTNode::Match {
+                scrutinee,
+                arms: arms.into_iter().map(|(value, body)| (value, block(body))).collect(),
+                default: block(default),
+            },
+            other => other,
+        }
+    }
+
+    // Whether a `break` in `body` leaves the loop it belongs to. `inner` holds the labels of the
+    // loops nested between that loop and the statement being checked.
+    fn breaks_out(body: &[ASTNode], inner: &mut Vec<Option<String>>) -> bool {
+        body.iter().any(|stmt| match stmt {
+            ASTNode::Break(None) => inner.is_empty(),
+            // Labels are never shadowed, so any label other than a nested loop's leaves this one
+            ASTNode::Break(Some(target)) => !inner.contains(&Some(target.clone())),
+            ASTNode::If {
+                then_branch,
+                else_branch,
+                ..
+            } => {
+                Optimizer::breaks_out(then_branch, inner)
+                    || Optimizer::breaks_out(else_branch, inner)
+            }
+            ASTNode::Match { arms, default, .. } => {
+                arms.iter()
+                    .any(|(_, arm)| Optimizer::breaks_out(arm, inner))
+                    || Optimizer::breaks_out(default, inner)
+            }
+            ASTNode::While { label: nested, body, .. }
+            | ASTNode::DoWhile { label: nested, body, .. }
+            | ASTNode::For { label: nested, body, .. } => {
+                inner.push(nested.clone());
+                let breaks = Optimizer::breaks_out(body, inner);
+                inner.pop();
+                breaks
+            }
+            _ => false,
+        })
+    }
+
+    // A pure subexpression (no rand() call, assignment or divisor check) repeated within one
+    // statement is computed once into a temporary. Nothing is hoisted out of a statement that
+    // assigns, since the repeats could see different values. Loop conditions are re-evaluated
+    // every iteration and are left alone; everything else is evaluated once per execution of its
+    // statement.
+    fn eliminate_common_subexpressions(&mut self, nodes: Vec<ASTNode>) -> Vec<ASTNode> {
+        let mut result = Vec::new();
+        for node in nodes {
+            match node {
+                ASTNode::Assignment {
+                    variable,
+                    mut value,
+                    storage,
+                    origin,
+                } => {
+                    result.extend(self.hoist_repeated(&mut value));
+                    result.push(ASTNode::Assignment {
+                        variable,
+                        value,
+                        storage,
+                        origin,
+                    });
+                }
+                ASTNode::MultiAssign { targets, mut values, origins } => {
+                    for value in &mut values {
+                        result.extend(self.hoist_repeated(value));
+                    }
+                    result.push(ASTNode::MultiAssign { targets, values, origins });
+                }
+                ASTNode::Print { mut parts, width, fd } => {
+                    result.extend(self.hoist_repeated_in(&mut parts));
+                    result.push(ASTNode::Print { parts, width, fd });
+                }
+                ASTNode::Printf { mut parts } => {
+                    result.extend(self.hoist_repeated_in(&mut parts));
+                    result.push(ASTNode::Printf { parts });
+                }
+                ASTNode::If {
+                    mut condition,
+                    then_branch,
+                    else_branch,
+                } => {
+                    result.extend(self.hoist_repeated(&mut condition));
+                    result.push(ASTNode::If {
+                        condition,
+                        then_branch: self.eliminate_common_subexpressions(then_branch),
+                        else_branch: self.eliminate_common_subexpressions(else_branch),
+                    });
+                }
+                ASTNode::While { label, condition, body } => {
+                    result.push(ASTNode::While {
+                        label,
+                        condition,
+                        body: self.eliminate_common_subexpressions(body),
+                    });
+                }
+                ASTNode::DoWhile { label, body, condition } => {
+                    result.push(ASTNode::DoWhile {
+                        label,
+                        body: self.eliminate_common_subexpressions(body),
+                        condition,
+                    });
+                }
+                ASTNode::For {
+                    label,
+                    variable,
+                    mut range_expr,
+                    body,
+                    origin,
+                } => {
+                    result.extend(self.hoist_repeated(&mut range_expr));
+                    result.push(ASTNode::For {
+                        label,
+                        variable,
+                        range_expr,
+                        body: self.eliminate_common_subexpressions(body),
+                        origin,
+                    });
+                }
+                ASTNode::Match {
+                    mut scrutinee,
+                    arms,
+                    default,
+                } => {
+                    result.extend(self.hoist_repeated(&mut scrutinee));
+                    result.push(ASTNode::Match {
+                        scrutinee,
+                        arms: arms
+                            .into_iter()
+                            .map(|(value, body)| (value, self.eliminate_common_subexpressions(body)))
+                            .collect(),
+                        default: self.eliminate_common_subexpressions(default),
+                    });
+                }
+                other => result.push(other),
+            }
+        }
+        result
+    }
+
+    // Each printed value is its own expression, evaluated once
+    fn hoist_repeated_in(&mut self, parts: &mut [FormatPart]) -> Vec<ASTNode> {
+        let mut hoisted = Vec::new();
+        for part in parts {
+            if let FormatPart::Value(value) = part {
+                hoisted.extend(self.hoist_repeated(value));
+            }
+        }
+        hoisted
+    }
+
+    // Replace the largest repeated subexpression with a temporary until none remain, returning
+    // the assignments that compute the temporaries. A `x / y` and `x % y` pair is first replaced
+    // by the two results of a single division.
+    fn hoist_repeated(&mut self, expr: &mut ASTNode) -> Vec<ASTNode> {
+        let mut prelude = Vec::new();
+        // In `(a + 1) + (a = 5) + (a + 1)` the two `a + 1` differ, so leave such expressions alone
+        if Optimizer::assigns(expr) {
+            return prelude;
+        }
+        while let Some((division, modulo)) = Optimizer::find_divmod(expr) {
+            let ASTNode::BinaryOp { left, right, .. } = division.clone() else {
+                unreachable!("find_divmod returns binary operations");
+            };
+            self.temp_counter += 1;
+            let quotient = format!(".cse_{}", self.temp_counter);
+            self.temp_counter += 1;
+            let remainder = format!(".cse_{}", self.temp_counter);
+            Optimizer::replace_subexpression(expr, &division, &quotient, &mut None);
+            Optimizer::replace_subexpression(expr, &modulo, &remainder, &mut None);
+            prelude.push(ASTNode::DivMod {
+                dividend: left,
+                divisor: right,
+                quotient,
+                remainder,
+            });
+        }
+        loop {
+            let mut counts = Vec::new();
+            Optimizer::count_subexpressions(expr, &mut counts);
+            let repeated = counts
+                .into_iter()
+                .filter(|(_, count, _)| *count > 1)
+                .max_by_key(|(_, _, size)| *size);
+            let Some((key, _, _)) = repeated else {
+                return prelude;
+            };
+
+            self.temp_counter += 1;
+            let temp = format!(".cse_{}", self.temp_counter);
+            let mut hoisted = None;
+            Optimizer::replace_subexpression(expr, &key, &temp, &mut hoisted);
+            let assignment = ASTNode::Assignment {
+                variable: temp,
+                value: Box::new(hoisted.unwrap()),
+                storage: None,
+                origin: Origin::default(),
+            };
+            prelude.extend(self.eliminate_common_subexpressions(vec![assignment]));
+        }
+    }
+
+    // Record (subexpression, occurrences, node count) for every compound subexpression,
+    // returning the node count of `node`
+    fn count_subexpressions(node: &ASTNode, counts: &mut Vec<(ASTNode, usize, usize)>) -> usize {
+        let size = match node {
+            // The right operand of `&&`/`||` may not run, so nothing in it is hoisted ahead
+            ASTNode::BinaryOp { left, operator, right } if operator == "&&" || operator == "||" => {
+                1 + Optimizer::count_subexpressions(left, counts)
+                    + Optimizer::count_subexpressions(right, &mut Vec::new())
+            }
+            ASTNode::BinaryOp { left, right, .. } => {
+                1 + Optimizer::count_subexpressions(left, counts)
+                    + Optimizer::count_subexpressions(right, counts)
+            }
+            ASTNode::Call { args, .. } => {
+                1 + args
+                    .iter()
+                    .map(|arg| Optimizer::count_subexpressions(arg, counts))
+                    .sum::<usize>()
+            }
+            _ => return 1,
+        };
+
+        // Two calls to rand() are two different numbers, so never merge anything containing one
+        if !Optimizer::is_pure(node) {
+            return size;
+        }
+        match counts.iter_mut().find(|(existing, _, _)| existing == node) {
+            Some(entry) => entry.1 += 1,
+            None => counts.push((node.clone(), 1, size)),
+        }
+        size
+    }
+
+    // A pure `x / y` in the expression together with a `x % y` on the same operands
+    fn find_divmod(expr: &ASTNode) -> Option<(ASTNode, ASTNode)> {
+        fn collect<'a>(node: &'a ASTNode, found: &mut Vec<&'a ASTNode>) {
+            match node {
+                ASTNode::BinaryOp { left, operator, .. } if operator == "&&" || operator == "||" => {
+                    found.push(node);
+                    collect(left, found);
+                }
+                ASTNode::BinaryOp { left, right, .. } => {
+                    found.push(node);
+                    collect(left, found);
+                    collect(right, found);
+                }
+                ASTNode::Call { args, .. } => {
+                    for arg in args {
+                        collect(arg, found);
+                    }
+                }
+                _ => {}
+            }
+        }
+        let mut operations = Vec::new();
+        collect(expr, &mut operations);
+        operations.iter().find_map(|division| {
+            let ASTNode::BinaryOp { left, operator, right } = division else {
+                return None;
+            };
+            if operator != "/" || !Optimizer::is_pure(division) {
+                return None;
+            }
+            let modulo = ASTNode::BinaryOp {
+                left: left.clone(),
+                operator: "%".to_string(),
+                right: right.clone(),
+            };
+            operations
+                .contains(&&modulo)
+                .then(|| ((*division).clone(), modulo))
+        })
+    }
+
+    // Whether evaluating the expression stores into a variable
+    fn assigns(node: &ASTNode) -> bool {
+        match node {
+            ASTNode::Assignment { .. } | ASTNode::Sequence(_) => true,
+            ASTNode::BinaryOp { left, right, .. } => {
+                Optimizer::assigns(left) || Optimizer::assigns(right)
+            }
+            ASTNode::Call { args, .. } => args.iter().any(Optimizer::assigns),
+            ASTNode::NonZero { value, .. } => Optimizer::assigns(value),
+            _ => false,
+        }
+    }
+
+    fn is_pure(node: &ASTNode) -> bool {
+        match node {
+            ASTNode::BinaryOp { left, right, .. } => {
+                Optimizer::is_pure(left) && Optimizer::is_pure(right)
+            }
+            ASTNode::Call { name, args } => name != "rand" && args.iter().all(Optimizer::is_pure),
+            // A failed check stops the program, so it can't be merged or moved
+            ASTNode::NonZero { .. } => false,
+            ASTNode::Assignment { .. } | ASTNode::Sequence(_) => false,
+            _ => true,
+        }
+    }
+
+    fn replace_subexpression(
+        node: &mut ASTNode,
+        key: &ASTNode,
+        temp: &str,
+        hoisted: &mut Option<ASTNode>,
+    ) {
+        if node == key {
+            let variable = ASTNode::Variable(temp.to_string(), Origin::default());
+            let original = std::mem::replace(node, variable);
+            hoisted.get_or_insert(original);
+            return;
+        }
+
+        match node {
+            ASTNode::BinaryOp { left, right, .. } => {
+                Optimizer::replace_subexpression(left, key, temp, hoisted);
+                Optimizer::replace_subexpression(right, key, temp, hoisted);
+            }
+            ASTNode::Call { args, .. } => {
+                for arg in args {
+                    Optimizer::replace_subexpression(arg, key, temp, hoisted);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+// Regenerates canonical source for --format: one statement per line, four-space indentation
+// and only the parentheses the parser needs. Comments are not preserved.
+struct Formatter {
+    lines: Vec<String>,
+    indent: usize,
+}
+
+impl Formatter {
+    fn format(nodes: &[ASTNode]) -> String {
+        let mut formatter = Formatter {
+            lines: Vec::new(),
+            indent: 0,
+        };
+        for node in nodes {
+            formatter.statement(node);
+        }
+        formatter.lines.join("\n") + "\n"
+    }
+
+    fn line(&mut self, text: &str) {
+        self.lines.push(format!("{}{}", "    ".repeat(self.indent), text));
+    }
+
+    fn body(&mut self, statements: &[ASTNode]) {
+        self.indent += 1;
+        for stmt in statements {
+            self.statement(stmt);
+        }
+        self.indent -= 1;
+    }
+
+    fn label(label: &Option<String>) -> String {
+        label.as_ref().map_or(String::new(), |name| format!("{}: ", name))
+    }
+
+    fn jump(&mut self, keyword: &str, label: &Option<String>) {
+        match label {
+            Some(name) => self.line(&format!("{} {}", keyword, name)),
+            None => self.line(keyword),
+        }
+    }
+
+    fn statement(&mut self, node: &ASTNode) {
+        match node {
+            ASTNode::Assignment {
+                variable,
+                value,
+                storage,
+                ..
+            } => {
+                let hint = storage.map_or(String::new(), |storage| format!("{} ", storage.keyword()));
+                self.line(&format!("{}{} = {}", hint, variable, Formatter::expression(value)));
+            }
+            ASTNode::StaticAssert { condition, .. } => {
+                self.line(&format!("static_assert({})", Formatter::expression(condition)));
+            }
+            ASTNode::Defer(statement) => {
+                // Format the statement on its own, then prefix its first line
+                let mut inner = Formatter {
+                    lines: Vec::new(),
+                    indent: self.indent,
+                };
+                inner.statement(statement);
+                let prefix = "    ".repeat(self.indent);
+                if let Some(first) = inner.lines.first_mut() {
+                    first.insert_str(prefix.len(), "defer ");
+                }
+                self.lines.extend(inner.lines);
+            }
+            ASTNode::Macro { name, params, body } => {
+                self.line(&format!(
+                    "macro {}({}) = {}",
+                    name,
+                    params.join(", "),
+                    Formatter::expression(body)
+                ));
+            }
+            ASTNode::MultiAssign { targets, values, .. } => {
+                let values: Vec<String> = values.iter().map(Formatter::expression).collect();
+                self.line(&format!("{} = {}", targets.join(", "), values.join(", ")));
+            }
+            ASTNode::Print { parts, width, fd } => {
+                let keyword = if *fd == 2 { "eprint" } else { "print" };
+                let mut args: Vec<String> = parts
+                    .iter()
+                    .map(|part| match part {
+                        FormatPart::Text(text) => format!("\"{}\"", Formatter::escape(text)),
+                        FormatPart::Value(value) => Formatter::expression(value),
+                    })
+                    .collect();
+                args.extend(width.map(|width| format!("width={}", width)));
+                self.line(&format!("{}({})", keyword, args.join(", ")));
+            }
+            ASTNode::Printf { parts } => {
+                let mut format = String::new();
+                let mut args = Vec::new();
+                for part in parts {
+                    match part {
+                        FormatPart::Text(text) => {
+                            format.push_str(&Formatter::escape(text).replace('%', "%%"))
+                        }
+                        FormatPart::Value(value) => {
+                            format.push_str("%d");
+                            args.push(format!(", {}", Formatter::expression(value)));
+                        }
+                    }
+                }
+                self.line(&format!("printf(\"{}\"{})", format, args.concat()));
+            }
+            ASTNode::If {
+                condition,
+                then_branch,
+                else_branch,
+            } => {
+                self.line(&format!("if ({}) {{", Formatter::expression(condition)));
+                self.body(then_branch);
+                if !else_branch.is_empty() {
+                    self.line("} else {");
+                    self.body(else_branch);
+                }
+                self.line("}");
+            }
+            ASTNode::While { label, condition, body } => {
+                self.line(&format!(
+                    "{}while ({}) {{",
+                    Formatter::label(label),
+                    Formatter::expression(condition)
+                ));
+                self.body(body);
+                self.line("}");
+            }
+            ASTNode::DoWhile { label, body, condition } => {
+                self.line(&format!("{}do {{", Formatter::label(label)));
+                self.body(body);
+                self.line(&format!("}} while ({})", Formatter::expression(condition)));
+            }
+            ASTNode::For {
+                label,
+                variable,
+                range_expr,
+                body,
+                ..
+            } => {
+                self.line(&format!(
+                    "{}for ({} in range({})) {{",
+                    Formatter::label(label),
+                    variable,
+                    Formatter::expression(range_expr)
+                ));
+                self.body(body);
+                self.line("}");
+            }
+            ASTNode::Match {
+                scrutinee,
+                arms,
+                default,
+            } => {
+                self.line(&format!("match {} {{", Formatter::expression(scrutinee)));
+                self.indent += 1;
+                for (value, body) in arms {
+                    self.line(&format!("{} {{", value));
+                    self.body(body);
+                    self.line("}");
+                }
+                if !default.is_empty() {
+                    self.line("else {");
+                    self.body(default);
+                    self.line("}");
+                }
+                self.indent -= 1;
+                self.line("}");
+            }
+            ASTNode::Break(label) => self.jump("break", label),
+            ASTNode::Continue(label) => self.jump("continue", label),
+            expression => {
+                let text = Formatter::expression(expression);
+                self.line(&text);
+            }
+        }
+    }
+
+    // The parser's default operator table; higher binds tighter
+    fn precedence(operator: &str) -> (u8, Associativity) {
+        BINARY_OPERATORS
+            .iter()
+            .find(|(name, _, _)| *name == operator)
+            .map_or((u8::MAX, Associativity::Left), |&(_, level, associativity)| {
+                (level, associativity)
+            })
+    }
+
+    // String literal contents with the escapes the lexer reads back
+    fn escape(text: &str) -> String {
+        let mut escaped = String::new();
+        for c in text.chars() {
+            match c {
+                '\n' => escaped.push_str("\\n"),
+                '\t' => escaped.push_str("\\t"),
+                '"' | '\\' => {
+                    escaped.push('\\');
+                    escaped.push(c);
+                }
+                c => escaped.push(c),
+            }
+        }
+        escaped
+    }
+
+    fn expression(node: &ASTNode) -> String {
+        match node {
+            ASTNode::Number(value) => value.to_string(),
+            ASTNode::Literal { text, .. } => text.clone(),
+            ASTNode::Folded { value, .. } => value.to_string(),
+            ASTNode::Variable(name, _) => name.clone(),
+            ASTNode::Call { name, args } => {
+                let args: Vec<String> = args.iter().map(Formatter::expression).collect();
+                format!("{}({})", name, args.join(", "))
+            }
+            ASTNode::BinaryOp {
+                left,
+                operator,
+                right,
+            } => {
+                let (level, associativity) = Formatter::precedence(operator);
+                // An operand at the parent's level needs parentheses unless the operator groups
+                // towards that side; non-associative operators (comparisons) group towards neither
+                let operand = |child: &ASTNode, grouping: Associativity| {
+                    let text = Formatter::expression(child);
+                    match child {
+                        ASTNode::BinaryOp { operator, .. } => {
+                            let (child_level, _) = Formatter::precedence(operator);
+                            if child_level < level || (child_level == level && associativity != grouping) {
+                                format!("({})", text)
+                            } else {
+                                text
+                            }
+                        }
+                        _ => text,
+                    }
+                };
+                format!(
+                    "{} {} {}",
+                    operand(left, Associativity::Left),
+                    operator,
+                    operand(right, Associativity::Right)
+                )
+            }
+            ASTNode::Assignment { variable, value, .. } => {
+                format!("({} = {})", variable, Formatter::expression(value))
+            }
+            ASTNode::NonZero { value, .. } => Formatter::expression(value),
+            ASTNode::Sequence(items) => {
+                let items: Vec<String> = items
+                    .iter()
+                    .map(|item| match item {
+                        ASTNode::Assignment { variable, value, .. } => {
+                            format!("{} = {}", variable, Formatter::expression(value))
+                        }
+                        item => Formatter::expression(item),
+                    })
+                    .collect();
+                format!("({})", items.join(", "))
+            }
+            other => panic!("Cannot format {:?} as an expression", other),
+        }
+    }
+}
+
+// Map a variable name to its assembler label. Plain ASCII alphanumeric names are used as-is;
+// every label the compiler generates contains '_', so they can't collide. Names containing '_'
+// or non-ASCII letters are spelled as the hex of their UTF-8 bytes ("v_..."), as are names nasm
+// would read as something else (`rax`), and compiler temporaries introduced by AST passes
+// (".name", which the lexer can't produce) become "__name".
+fn mangle(name: &str) -> String {
+    if let Some(temp) = name.strip_prefix('.') {
+        format!("__{}", temp)
+    } else if name.chars().all(|c| c.is_ascii_alphanumeric()) && !is_reserved_label(name) {
+        name.to_string()
+    } else {
+        let hex: String = name.bytes().map(|b| format!("{:02x}", b)).collect();
+        format!("v_{}", hex)
+    }
+}
+
+// Words nasm reserves in operands, the compiler's own labels without a '_', and the libc
+// functions it calls
+const RESERVED_LABELS: &[&str] = &[
+    "rax", "rbx", "rcx", "rdx", "rsi", "rdi", "rbp", "rsp", "rip", "eax", "ebx", "ecx", "edx",
+    "esi", "edi", "ebp", "esp", "eip", "ax", "bx", "cx", "dx", "si", "di", "bp", "sp", "al", "bl",
+    "cl", "dl", "ah", "bh", "ch", "dh", "sil", "dil", "bpl", "spl", "cs", "ds", "es", "fs", "gs",
+    "ss", "byte", "word", "dword", "qword", "tword", "oword", "yword", "zword", "rel", "abs",
+    "seg", "wrt", "strict", "near", "far", "short", "to", "default", "section", "segment",
+    "global", "extern", "times", "db", "dw", "dd", "dq", "dt", "resb", "resw", "resd", "resq",
+    "equ", "buffer", "newline", "main", "printf", "exit",
+];
+
+// Whether nasm would not read `name` as a plain label: a reserved word or a numbered register
+// (`r8`, `r8d`, `xmm0`, `st1`, `k1`, ...), in any case. Matching a little more than the real
+// registers only costs a hex spelling.
+fn is_reserved_label(name: &str) -> bool {
+    let name = name.to_ascii_lowercase();
+    if RESERVED_LABELS.contains(&name.as_str()) {
+        return true;
+    }
+    ["r", "xmm", "ymm", "zmm", "mm", "st", "cr", "dr", "k"].iter().any(|family| {
+        name.strip_prefix(family).is_some_and(|rest| {
+            let number = rest.trim_end_matches(['d', 'w', 'b', 'l']);
+            !number.is_empty() && number.chars().all(|c| c.is_ascii_digit())
+        })
+    })
+}
+
+// A function callable from Viper code. The handler is given the call's argument nodes and emits
+// code that leaves the result in rax, evaluating the arguments itself with `generate`. The
+// optimizer assumes every builtin except `rand` is free of side effects.
+type BuiltinHandler = fn(&mut CodeGenerator, &[ASTNode]);
+
+#[derive(Clone, Copy)]
+struct Builtin {
+    arity: usize,
+    emit: BuiltinHandler,
+}
+
+// The functions every program can call (besides `range`, which the parser handles). Embedders
+// add their own by inserting into this map and passing it to both `Parser::with_builtins` and
+// `CodeGenerator::with_builtins`.
+fn default_builtins() -> HashMap<String, Builtin> {
+    let builtins: [(&str, usize, BuiltinHandler); 3] = [
+        ("isqrt", 1, CodeGenerator::emit_isqrt),
+        ("gcd", 2, CodeGenerator::emit_gcd),
+        ("rand", 1, CodeGenerator::emit_rand),
+    ];
+    builtins
+        .into_iter()
+        .map(|(name, arity, emit)| (name.to_string(), Builtin { arity, emit }))
+        .collect()
+}
+
+// Instructions are buffered in memory and only written out once generation has finished
+struct CodeGenerator {
+    output: Vec<String>,
+    label_counter: usize,
+    temporaries: Vec<String>,
+    profile: bool,
+    instruction_limit: usize,
+    // Set once an instruction was dropped for going past instruction_limit
+    too_large: bool,
+    // Enclosing loops, innermost last: (label, break target, continue target)
+    loops: Vec<(Option<String>, String, String)>,
+    // Initial rand() state; without one, the first call seeds it from the timestamp counter
+    seed: Option<u64>,
+    uses_rand: bool,
+    // Whether any NonZero check jumps to the division-by-zero handler
+    checks_divisors: bool,
+    // Print through printf and start at `main`, for linking against libc
+    libc: bool,
+    target: TargetOs,
+    // Variables kept in the stack frame rather than .bss, in slot order
+    locals: Vec<String>,
+    builtins: HashMap<String, Builtin>,
+    // Labels and contents of printf text, for the footer's .data section
+    texts: Vec<(String, String)>,
+    // Move else-branches out of line, after the exit code, collecting them in `cold`
+    branch_layout: bool,
+    cold: Vec<String>,
+    // Comment each constant -O folded with the expression it came from
+    fold_comments: bool,
+    // Deferred statements in the order they were reached; the footer runs them in reverse
+    deferred: Vec<ASTNode>,
+    // Read VIPER_BASE at startup and print in hexadecimal when it is 16
+    base_toggle: bool,
+    // Labels of the subroutines emitted so far
+    subroutines: HashSet<String>,
+}
+
+const DEFAULT_INSTRUCTION_LIMIT: usize = 1_000_000;
+const PROFILE_COUNTER: &str = "__profile_blocks";
+const PROFILE_MESSAGE: &str = "basic blocks executed: ";
+const RAND_STATE: &str = "__rand_state";
+const DIVISION_MESSAGE: &str = "division by zero at line ";
+// Environment entry that makes a program built with --runtime-base-toggle print in hexadecimal
+const BASE_VARIABLE: &str = "VIPER_BASE=16";
+
+// Operating system the generated program runs on
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum TargetOs {
+    Linux,
+    MacOs,
+}
+
+struct SyscallTable {
+    write: u32,
+    exit: u32,
+}
+
+const LINUX_SYSCALLS: SyscallTable = SyscallTable { write: 1, exit: 60 };
+// macOS uses the BSD numbers offset by 0x2000000, the Unix syscall class
+const MACOS_SYSCALLS: SyscallTable = SyscallTable {
+    write: 0x2000004,
+    exit: 0x2000001,
+};
+
+impl TargetOs {
+    fn syscalls(self) -> &'static SyscallTable {
+        match self {
+            TargetOs::Linux => &LINUX_SYSCALLS,
+            TargetOs::MacOs => &MACOS_SYSCALLS,
+        }
+    }
+
+    fn entry_point(self) -> &'static str {
+        match self {
+            TargetOs::Linux => "_start",
+            TargetOs::MacOs => "_main",
+        }
+    }
+
+    fn object_format(self) -> &'static str {
+        match self {
+            TargetOs::Linux => "elf64",
+            TargetOs::MacOs => "macho64",
+        }
+    }
+}
+
+impl CodeGenerator {
+    fn new() -> Self {
+        CodeGenerator {
+            output: Vec::new(),
+            label_counter: 0,
+            temporaries: Vec::new(),
+            profile: false,
+            instruction_limit: DEFAULT_INSTRUCTION_LIMIT,
+            too_large: false,
+            loops: Vec::new(),
+            seed: None,
+            uses_rand: false,
+            checks_divisors: false,
+            libc: false,
+            target: TargetOs::Linux,
+            locals: Vec::new(),
+            builtins: default_builtins(),
+            texts: Vec::new(),
+            branch_layout: false,
+            cold: Vec::new(),
+            fold_comments: false,
+            deferred: Vec::new(),
+            base_toggle: false,
+            subroutines: HashSet::new(),
+        }
+    }
+
+    // Lay out `if` statements so the then-branch falls through to the code that follows
+    fn with_branch_layout(mut self, branch_layout: bool) -> Self {
+        self.branch_layout = branch_layout;
+        self
+    }
+
+    fn with_builtins(mut self, builtins: HashMap<String, Builtin>) -> Self {
+        self.builtins = builtins;
+        self
+    }
+
+    fn with_fold_comments(mut self, fold_comments: bool) -> Self {
+        self.fold_comments = fold_comments;
+        self
+    }
+
+    fn with_target(mut self, target: TargetOs) -> Self {
+        self.target = target;
+        self
+    }
+
+    fn with_libc(mut self, libc: bool) -> Self {
+        self.libc = libc;
+        self
+    }
+
+    fn with_seed(mut self, seed: Option<u64>) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    // Fail with ProgramTooLarge rather than buffering an unbounded amount of assembly
+    fn with_instruction_limit(mut self, instruction_limit: usize) -> Self {
+        self.instruction_limit = instruction_limit;
+        self
+    }
+
+    // Let the environment of the running program choose the base numbers are printed in
+    fn with_base_toggle(mut self, base_toggle: bool) -> Self {
+        self.base_toggle = base_toggle;
+        self
+    }
+
+    // Count every basic block the program enters and report the total on exit
+    fn with_profiling(mut self, profile: bool) -> Self {
+        self.profile = profile;
+        self
+    }
+
+    fn emit(&mut self, instruction: &str) {
+        if self.output.len() + self.cold.len() >= self.instruction_limit {
+            self.too_large = true;
+            return;
+        }
+        self.output.push(instruction.to_string());
+    }
+
+    fn emit_label(&mut self, label: &str) {
+        self.emit(&format!("{}:", label));
+        self.emit_block_count();
+    }
+
+    // `mov rax, value`, or the shorter `xor eax, eax` for zero. The xor clobbers the flags, but
+    // loading a value never happens between a comparison and its jump.
+    fn load_rax(value: i64) -> String {
+        if value == 0 {
+            "    xor eax, eax".to_string()
+        } else {
+            format!("    mov rax, {}", value)
+        }
+    }
+
+    // A conditional jump that falls through also starts a new basic block
+    fn emit_jump(&mut self, jump: &str, label: &str) {
+        self.emit(&format!("    {} {}", jump, label));
+        if jump != "jmp" {
+            self.emit_block_count();
+        }
+    }
+
+    fn emit_block_count(&mut self) {
+        if self.profile {
+            self.emit(&format!("    inc qword [{}]", PROFILE_COUNTER));
+        }
+    }
+
+    // Print the integer in rax followed by a newline to the given file descriptor, padded on the
+    // left with spaces to `width` columns (at most 20; 0 for no padding)
+    fn emit_print_rax(&mut self, fd: u8, width: usize) {
+        // printf buffers stdout, so output of its own must not bypass it with raw writes
+        if self.libc && fd == 1 {
+            if width > 0 {
+                self.emit("    mov rdx, rax");
+                self.emit(&format!("    mov esi, {}", width));
+                self.emit("    lea rdi, [padded_format]");
+            } else {
+                self.emit("    mov rsi, rax");
+                self.emit("    lea rdi, [print_format]");
+            }
+            self.emit("    xor eax, eax"); // variadic call: no vector registers used
+            self.emit_external_call("printf");
+            return;
+        }
+        self.emit_print_number(fd, width);
+        self.emit("    lea rsi, [newline]");
+        self.emit("    mov rdx, 1");
+        self.emit(&format!("    mov rax, {}", self.target.syscalls().write));
+        self.emit(&format!("    mov rdi, {}", fd));
+        self.emit("    syscall");
+    }
+
+    // Print the integer in rax, with no newline, to the given file descriptor. In libc mode only
+    // emit_print_rax pads.
+    fn emit_print_number(&mut self, fd: u8, width: usize) {
+        if self.libc && fd == 1 {
+            self.emit("    mov rsi, rax");
+            self.emit("    lea rdi, [number_format]");
+            self.emit("    xor eax, eax");
+            self.emit_external_call("printf");
+            return;
+        }
+        self.emit("    lea rcx, [buffer]");
+        self.emit("    call int_to_string");
+        if width > 0 {
+            // The digits end at buffer + 20; prepend spaces until `width` characters are used
+            let pad = self.new_label("pad");
+            let padded = self.new_label("padded");
+            self.emit(&format!("    lea rdx, [buffer + {}]", 20 - width));
+            self.emit_label(&pad);
+            self.emit("    cmp rcx, rdx");
+            self.emit_jump("jbe", &padded);
+            self.emit("    dec rcx");
+            self.emit("    mov byte [rcx], ' '");
+            self.emit_jump("jmp", &pad);
+            self.emit_label(&padded);
+        }
+        self.emit("    lea rdx, [buffer]");
+        self.emit("    add rdx, 20");
+        self.emit("    sub rdx, rcx");
+        self.emit("    mov rsi, rcx");
+        self.emit(&format!("    mov rax, {}", self.target.syscalls().write));
+        self.emit(&format!("    mov rdi, {}", fd));
+        self.emit("    syscall");
+    }
+
+    // Print text and numbers one after another, with no newline
+    fn emit_print_parts(&mut self, fd: u8, parts: &[FormatPart]) {
+        for part in parts {
+            match part {
+                FormatPart::Text(text) => self.emit_print_text(fd, text),
+                FormatPart::Value(value) => {
+                    self.generate_node(value);
+                    self.emit_print_number(fd, 0);
+                }
+            }
+        }
+    }
+
+    // Print literal text to file descriptor `fd` (stdout or stderr). Each distinct text is placed
+    // once in the footer's .data section, NUL-terminated for printf's `%s`.
+    fn emit_print_text(&mut self, fd: u8, text: &str) {
+        let label = match self.texts.iter().find(|(_, existing)| existing == text) {
+            Some((label, _)) => label.clone(),
+            None => {
+                let label = format!("__{}", self.new_label("text"));
+                self.texts.push((label.clone(), text.to_string()));
+                label
+            }
+        };
+        self.emit(&format!("    lea rsi, [{}]", label));
+        if self.libc && fd == 1 {
+            self.emit("    lea rdi, [text_format]");
+            self.emit("    xor eax, eax");
+            self.emit_external_call("printf");
+            return;
+        }
+        self.emit(&format!("    mov rdx, {}", text.len()));
+        self.emit(&format!("    mov rax, {}", self.target.syscalls().write));
+        self.emit(&format!("    mov rdi, {}", fd));
+        self.emit("    syscall");
+    }
+
+    // Call a System V ABI function, which requires rsp to be 16-byte aligned at the call. rbp is
+    // callee-saved, so it holds the original rsp across the call.
+    fn emit_external_call(&mut self, function: &str) {
+        self.emit("    mov rbp, rsp");
+        self.emit("    and rsp, -16");
+        self.emit(&format!("    call {}", function));
+        self.emit("    mov rsp, rbp");
+    }
+
+    fn write_to(&self, output_path: &str) {
+        let mut file = File::create(output_path).expect("Unable to create file");
+        for line in &self.output {
+            writeln!(file, "{}", line).expect("Unable to write to file");
+        }
+    }
+
+    // Address of a variable's slot: its .bss symbol, or an offset into the frame r15 points at.
+    // r15 is callee-saved, so it survives calls into libc.
+    fn slot(&self, name: &str) -> String {
+        match self.locals.iter().position(|local| local == name) {
+            Some(index) => format!("r15 - {}", 8 * (index + 1)),
+            None => mangle(name),
+        }
+    }
+
+    fn emit_header(&mut self, variables: &[String], locals: &[String]) {
+        self.locals = locals.to_vec();
+        if self.target == TargetOs::MacOs {
+            // Mach-O has no 32-bit absolute addressing, so memory operands (and the `lea`s that
+            // take data addresses) must be RIP-relative
+            self.emit("default rel");
+        }
+        self.emit("section .bss");
+        for var in variables.iter().filter(|var| !locals.contains(var)) {
+            self.emit(&format!("{} resq 1", mangle(var)));
+        }
+        self.emit("buffer resb 20");
+
+        self.emit("section .data");
+        self.emit("newline db 0xA, 0");
+        if self.libc {
+            self.emit("print_format db \"%ld\", 0xA, 0");
+            self.emit("padded_format db \"%*ld\", 0xA, 0");
+            self.emit("number_format db \"%ld\", 0");
+            self.emit("text_format db \"%s\", 0");
+        }
+        if self.profile {
+            self.emit(&format!("profile_message db \"{}\", 0", PROFILE_MESSAGE));
+            self.temporaries.push(PROFILE_COUNTER.to_string());
+        }
+        if self.base_toggle {
+            self.emit("print_base dq 10");
+            self.emit(&format!("base_variable db \"{}\", 0", BASE_VARIABLE));
+        }
+
+        self.emit("section .text");
+        if self.libc {
+            // libc's own startup code calls main once the C runtime is initialized
+            self.emit("extern printf, exit");
+            self.emit("global main");
+            self.emit("main:");
+        } else {
+            let entry = self.target.entry_point();
+            self.emit(&format!("global {}", entry));
+            self.emit(&format!("{}:", entry));
+        }
+        if self.base_toggle {
+            if self.libc || self.target == TargetOs::MacOs {
+                // Entered as main(argc, argv, envp)
+                self.emit("    mov rsi, rdx");
+            } else {
+                // The stack holds argc, then argv and the environment, each ending in a null
+                self.emit("    mov rax, [rsp]");
+                self.emit("    lea rsi, [rsp + rax * 8 + 16]");
+            }
+            self.emit("    call read_print_base");
+        }
+        // Linux enters _start with rsp 16-byte aligned, but main is entered 8 bytes off; align
+        // it either way, since code following the System V ABI (libc) needs the alignment
+        self.emit("    and rsp, -16");
+        if !locals.is_empty() {
+            // Stack frame for `local` variables, rounded up to keep rsp 16-byte aligned
+            self.emit("    mov r15, rsp");
+            self.emit(&format!("    sub rsp, {}", (8 * locals.len()).next_multiple_of(16)));
+        }
+        self.emit_block_count();
+    }
+
+    fn emit_footer(&mut self) -> Result<(), CompileError> {
+        for statement in std::mem::take(&mut self.deferred).iter().rev() {
+            self.generate_node(statement);
+        }
+        if self.profile {
+            // Report on stderr so the program's own output is unchanged
+            self.emit("    lea rsi, [profile_message]");
+            self.emit(&format!("    mov rdx, {}", PROFILE_MESSAGE.len()));
+            self.emit(&format!("    mov rax, {}", self.target.syscalls().write));
+            self.emit("    mov rdi, 2");
+            self.emit("    syscall");
+            self.emit(&format!("    mov rax, [{}]", PROFILE_COUNTER));
+            self.emit_print_rax(2, 0);
+        }
+
+        if self.libc {
+            // exit() rather than the raw syscall, so printf's buffered output is flushed
+            self.emit("    xor edi, edi");
+            self.emit_external_call("exit");
+        } else {
+            self.emit(&format!("    mov rax, {}       ; syscall: exit", self.target.syscalls().exit));
+            self.emit("    xor rdi, rdi      ; return code: 0");
+            self.emit("    syscall");
+        }
+        if !self.cold.is_empty() {
+            self.emit("; Out-of-line else branches");
+            let cold = std::mem::take(&mut self.cold);
+            self.output.extend(cold);
+        }
+        if self.checks_divisors {
+            self.emit_subroutine_once(
+                "division_by_zero",
+                &["Entered with the source line in RAX; reports on stderr and exits with status 1"],
+                CodeGenerator::emit_division_handler,
+            );
+        }
+        self.emit_subroutine_once(
+            "int_to_string",
+            &["Subroutine to convert an integer in RAX to a string in the buffer"],
+            CodeGenerator::emit_int_to_string,
+        );
+        if self.base_toggle {
+            self.emit_subroutine_once(
+                "read_print_base",
+                &[
+                    "Subroutine to switch printing to hexadecimal if the environment in RSI (an",
+                    "array of strings ending in a null pointer) sets VIPER_BASE=16",
+                ],
+                CodeGenerator::emit_read_print_base,
+            );
+        }
+
+        // Compiler temporaries live in their own .bss block so they never alias user variables
+        if !self.temporaries.is_empty() {
+            self.emit("section .bss");
+            for temp in self.temporaries.clone() {
+                self.emit(&format!("{} resq 1", temp));
+            }
+        }
+        if self.uses_rand || self.checks_divisors || !self.texts.is_empty() {
+            self.emit("section .data");
+        }
+        if self.checks_divisors {
+            self.emit(&format!("division_message db \"{}\", 0", DIVISION_MESSAGE));
+        }
+        if self.uses_rand {
+            self.emit(&format!("{} dq {}", RAND_STATE, self.seed.unwrap_or(0)));
+        }
+        for (label, text) in self.texts.clone() {
+            let bytes: Vec<String> = text.bytes().chain([0]).map(|b| b.to_string()).collect();
+            self.emit(&format!("{} db {}", label, bytes.join(", ")));
+        }
+        self.check_size()
+    }
+
+    // Emit the comment lines, `name:` and the body, unless a subroutine of that name is already
+    // in the output. Every shared helper goes through here, so features that need the same one
+    // never emit it twice.
+    fn emit_subroutine_once(&mut self, name: &str, comment: &[&str], body: fn(&mut Self)) {
+        if !self.subroutines.insert(name.to_string()) {
+            return;
+        }
+        for line in comment {
+            self.emit(&format!("; {}", line));
+        }
+        self.emit(&format!("{}:", name));
+        body(self);
+    }
+
+    fn emit_division_handler(&mut self) {
+        self.emit("    push rax");
+        self.emit("    lea rsi, [division_message]");
+        self.emit(&format!("    mov rdx, {}", DIVISION_MESSAGE.len()));
+        self.emit(&format!("    mov rax, {}", self.target.syscalls().write));
+        self.emit("    mov rdi, 2");
+        self.emit("    syscall");
+        self.emit("    pop rax");
+        self.emit_print_rax(2, 0);
+        if self.libc {
+            self.emit("    mov edi, 1");
+            self.emit_external_call("exit");
+        } else {
+            self.emit(&format!("    mov rax, {}       ; syscall: exit", self.target.syscalls().exit));
+            self.emit("    mov rdi, 1        ; return code: 1");
+            self.emit("    syscall");
+        }
+    }
+
+    // The buffer holds the longest result, the sign and 19 digits of i64::MIN. Negating i64::MIN
+    // leaves it unchanged, but read as unsigned it is the right magnitude.
+    fn emit_int_to_string(&mut self) {
+        self.emit("    mov r8, rax               ; Keep the sign for the end");
+        self.emit("    test rax, rax");
+        self.emit("    jns .positive");
+        self.emit("    neg rax                   ; Convert the magnitude");
+        self.emit(".positive:");
+        self.emit("    xor rdx, rdx              ; Clear rdx (remainder)");
+        if self.base_toggle {
+            self.emit("    mov rbx, [print_base]     ; Divisor: 10 or 16");
+        } else {
+            self.emit("    mov rbx, 10               ; Divisor for decimal system");
+        }
+        self.emit("    add rcx, 20               ; Move pointer to the end of the buffer");
+        self.emit("    dec rcx                   ; Reserve space for the last character");
+        self.emit(".convert_loop:");
+        self.emit("    xor rdx, rdx              ; Clear rdx before division");
+        self.emit("    div rbx                   ; Divide rax by 10, remainder in rdx");
+        self.emit("    add dl, '0'               ; Convert remainder to ASCII");
+        if self.base_toggle {
+            self.emit("    cmp dl, '9'");
+            self.emit("    jbe .digit");
+            self.emit("    add dl, 'a' - '9' - 1     ; Hexadecimal digits past 9 are a-f");
+            self.emit(".digit:");
+        }
+        self.emit("    mov [rcx], dl             ; Store the ASCII character in the buffer");
+        self.emit("    dec rcx                   ; Move to the previous position in the buffer");
+        self.emit("    test rax, rax             ; Check if quotient is 0");
+        self.emit("    jnz .convert_loop         ; Repeat if not 0");
+        self.emit("    inc rcx                   ; Adjust pointer to the start of the string");
+        self.emit("    test r8, r8");
+        self.emit("    jns .unsigned");
+        self.emit("    dec rcx");
+        self.emit("    mov byte [rcx], '-'");
+        self.emit(".unsigned:");
+        self.emit("    ret");
+    }
+
+    fn emit_read_print_base(&mut self) {
+        self.emit(".next_variable:");
+        self.emit("    mov rdi, [rsi]");
+        self.emit("    test rdi, rdi");
+        self.emit("    jz .done");
+        self.emit("    lea rcx, [base_variable]");
+        self.emit(".compare:");
+        self.emit("    mov al, [rcx]");
+        self.emit("    cmp al, [rdi]");
+        self.emit("    jne .skip");
+        self.emit("    inc rcx");
+        self.emit("    inc rdi");
+        self.emit("    test al, al");
+        self.emit("    jnz .compare");
+        self.emit("    mov qword [print_base], 16");
+        if self.libc {
+            // printf formats `%ld` in hexadecimal as `%lx`
+            self.emit("    mov byte [print_format + 2], 'x'");
+            self.emit("    mov byte [padded_format + 3], 'x'");
+            self.emit("    mov byte [number_format + 2], 'x'");
+        }
+        self.emit("    ret");
+        self.emit(".skip:");
+        self.emit("    add rsi, 8");
+        self.emit("    jmp .next_variable");
+        self.emit(".done:");
         self.emit("    ret");
     }
 
+    // Post-pass for -O: a jump to a label that is immediately followed by another `jmp` is
+    // retargeted to that jump's destination (following whole chains), a `jmp` to the very next
+    // instruction is dropped, and labels nothing refers to any more are removed
+    fn thread_jumps(&mut self) {
+        fn label_of(line: &str) -> Option<&str> {
+            line.strip_suffix(':').filter(|label| !label.contains(char::is_whitespace))
+        }
+        fn jump_of(line: &str) -> Option<(&str, &str)> {
+            let (op, target) = line.trim_start().split_once(' ')?;
+            (op.starts_with('j') && !target.contains([' ', ','])).then_some((op, target))
+        }
+        // Index of the first instruction at or after `start`, skipping labels and comments
+        fn next_instruction(lines: &[String], start: usize) -> Option<usize> {
+            (start..lines.len()).find(|&i| {
+                label_of(&lines[i]).is_none() && !lines[i].trim_start().starts_with(';')
+            })
+        }
+
+        loop {
+            let mut forwards = HashMap::new();
+            for (i, line) in self.output.iter().enumerate() {
+                if let Some(label) = label_of(line) {
+                    let next = next_instruction(&self.output, i + 1);
+                    if let Some(("jmp", target)) = next.and_then(|j| jump_of(&self.output[j])) {
+                        forwards.insert(label.to_string(), target.to_string());
+                    }
+                }
+            }
+            let resolve = |label: &str| {
+                let mut target = label.to_string();
+                // Bounded so a cycle of jumps (an empty infinite loop) can't hang the compiler
+                for _ in 0..forwards.len() {
+                    match forwards.get(&target) {
+                        Some(next) if *next != target => target = next.clone(),
+                        _ => break,
+                    }
+                }
+                target
+            };
+
+            let mut changed = false;
+            let mut threaded = Vec::with_capacity(self.output.len());
+            for (i, line) in self.output.iter().enumerate() {
+                match jump_of(line) {
+                    Some((op, target)) => {
+                        let target = resolve(target);
+                        let falls_through = op == "jmp"
+                            && self.output[i + 1..]
+                                .iter()
+                                .take_while(|next| label_of(next).is_some())
+                                .any(|next| label_of(next) == Some(target.as_str()));
+                        if falls_through {
+                            changed = true;
+                            continue;
+                        }
+                        let rewritten = format!("    {} {}", op, target);
+                        changed |= rewritten != *line;
+                        threaded.push(rewritten);
+                    }
+                    None => threaded.push(line.clone()),
+                }
+            }
+
+            let referenced: HashSet<String> = threaded
+                .iter()
+                .filter(|line| label_of(line).is_none())
+                .flat_map(|line| line.split(|c: char| !(c.is_alphanumeric() || c == '_' || c == '.')))
+                .map(str::to_string)
+                .collect();
+            let before = threaded.len();
+            threaded.retain(|line| label_of(line).is_none_or(|label| referenced.contains(label)));
+            changed |= threaded.len() != before;
+
+            self.output = threaded;
+            if !changed {
+                return;
+            }
+        }
+    }
+
+    // Built-in function handlers, registered in default_builtins()
+    fn emit_isqrt(&mut self, args: &[ASTNode]) {
+        // Largest r with r * r <= x, one result bit per iteration: `bit` walks down the even
+        // powers of two and the remainder x - r * r stays in rcx. x <= 0 gives 0.
+        let shift_start = self.new_label("isqrt_shift");
+        let loop_start = self.new_label("isqrt_loop");
+        let smaller = self.new_label("isqrt_smaller");
+        let loop_end = self.new_label("end_isqrt");
+        self.generate_node(&args[0]);
+        self.emit("    mov rcx, rax");
+        self.emit(&CodeGenerator::load_rax(0));
+        self.emit("    test rcx, rcx");
+        self.emit_jump("jle", &loop_end);
+        self.emit(&format!("    mov rbx, {}", 1i64 << 62));
+        // Start from the largest power of four not above x
+        self.emit_label(&shift_start);
+        self.emit("    cmp rbx, rcx");
+        self.emit_jump("jbe", &loop_start);
+        self.emit("    shr rbx, 2");
+        self.emit_jump("jmp", &shift_start);
+        self.emit_label(&loop_start);
+        self.emit("    test rbx, rbx");
+        self.emit_jump("jz", &loop_end);
+        self.emit("    lea rdx, [rax + rbx]");
+        self.emit("    shr rax, 1");
+        self.emit("    cmp rcx, rdx");
+        self.emit_jump("jb", &smaller);
+        self.emit("    sub rcx, rdx");
+        self.emit("    add rax, rbx");
+        self.emit_label(&smaller);
+        self.emit("    shr rbx, 2");
+        self.emit_jump("jmp", &loop_start);
+        self.emit_label(&loop_end);
+    }
+
+    fn emit_gcd(&mut self, args: &[ASTNode]) {
+        // Euclid's algorithm: (a, b) -> (b, a mod b) until b is 0
+        let loop_start = self.new_label("gcd_loop");
+        let loop_end = self.new_label("end_gcd");
+        self.generate_node(&args[0]);
+        self.emit("    push rax");
+        self.generate_node(&args[1]);
+        self.emit("    mov rbx, rax");
+        self.emit("    pop rax");
+        // The gcd of the magnitudes, so the unsigned `div` below only sees non-negative values
+        for register in ["rax", "rbx"] {
+            self.emit(&format!("    mov rdx, {}", register));
+            self.emit(&format!("    neg {}", register));
+            self.emit(&format!("    cmovs {}, rdx", register));
+        }
+        self.emit_label(&loop_start);
+        self.emit("    test rbx, rbx");
+        self.emit_jump("jz", &loop_end);
+        self.emit("    xor rdx, rdx");
+        self.emit("    div rbx");
+        self.emit("    mov rax, rbx");
+        self.emit("    mov rbx, rdx");
+        self.emit_jump("jmp", &loop_start);
+        self.emit_label(&loop_end);
+    }
+
+    fn emit_rand(&mut self, args: &[ASTNode]) {
+        // 64-bit LCG (Knuth's MMIX constants); the high bits are the best mixed,
+        // so the result is (state >> 33) mod |n|, in [0, |n|)
+        self.uses_rand = true;
+        self.generate_node(&args[0]);
+        self.emit("    push rax");
+        self.emit(&format!("    mov rax, [{}]", RAND_STATE));
+        if self.seed.is_none() {
+            let seeded = self.new_label("rand_seeded");
+            self.emit("    test rax, rax");
+            self.emit_jump("jnz", &seeded);
+            self.emit("    rdtsc");
+            self.emit("    shl rdx, 32");
+            self.emit("    or rax, rdx");
+            self.emit_label(&seeded);
+        }
+        self.emit("    mov rbx, 6364136223846793005");
+        self.emit("    imul rax, rbx");
+        self.emit("    mov rbx, 1442695040888963407");
+        self.emit("    add rax, rbx");
+        self.emit(&format!("    mov [{}], rax", RAND_STATE));
+        self.emit("    shr rax, 33");
+        self.emit("    pop rbx");
+        // The magnitude of n, as in gcd, so the unsigned `div` below picks within the range
+        self.emit("    mov rdx, rbx");
+        self.emit("    neg rbx");
+        self.emit("    cmovs rbx, rdx");
+        // rand(0) has no range to pick from; it gives 0 rather than a division fault
+        let done = self.new_label("rand_done");
+        self.emit("    test rbx, rbx");
+        self.emit("    cmovz rax, rbx");
+        self.emit_jump("jz", &done);
+        self.emit("    xor rdx, rdx");
+        self.emit("    div rbx");
+        self.emit("    mov rax, rdx");
+        self.emit_label(&done);
+    }
+
     fn new_label(&mut self, prefix: &str) -> String {
         self.label_counter += 1;
         format!("{}_{}", prefix, self.label_counter)
     }
 
-    fn generate(&mut self, node: &ASTNode) {
+    // User names containing '_' are always mangled, so "__" keeps temporaries out of their way
+    fn new_temp(&mut self, prefix: &str) -> String {
+        let temp = format!("__{}", self.new_label(prefix));
+        self.temporaries.push(temp.clone());
+        temp
+    }
+
+    // Generate one top-level statement. Past the instruction limit nothing more is buffered, and
+    // the statement fails with ProgramTooLarge.
+    fn generate(&mut self, node: &ASTNode) -> Result<(), CompileError> {
+        self.generate_node(node);
+        self.check_size()
+    }
+
+    fn check_size(&self) -> Result<(), CompileError> {
+        if self.too_large {
+            return Err(CompileError::ProgramTooLarge {
+                limit: self.instruction_limit,
+            });
+        }
+        Ok(())
+    }
+
+    fn generate_node(&mut self, node: &ASTNode) {
         match node {
-            ASTNode::Assignment { variable, value } => {
-                self.generate(value);
-                self.emit(&format!("    mov [{}], rax", variable));
+            ASTNode::Assignment { variable, value, .. } => {
+                self.generate_node(value);
+                self.emit(&format!("    mov [{}], rax", self.slot(variable)));
+            }
+            ASTNode::DivMod {
+                dividend,
+                divisor,
+                quotient,
+                remainder,
+            } => {
+                self.generate_node(dividend);
+                self.emit("    push rax");
+                self.generate_node(divisor);
+                self.emit("    mov rbx, rax");
+                self.emit("    pop rax");
+                self.emit("    cqo");
+                self.emit("    idiv rbx");
+                self.emit(&format!("    mov [{}], rax", self.slot(quotient)));
+                self.emit(&format!("    mov [{}], rdx", self.slot(remainder)));
+            }
+            ASTNode::NonZero { value, line } => {
+                self.checks_divisors = true;
+                let nonzero = self.new_label("nonzero");
+                self.generate_node(value);
+                self.emit("    test rax, rax");
+                self.emit_jump("jnz", &nonzero);
+                self.emit(&format!("    mov rax, {}", line));
+                self.emit_jump("jmp", "division_by_zero");
+                self.emit_label(&nonzero);
+            }
+            // Every call was replaced by the body before code generation
+            ASTNode::Macro { .. } => {}
+            ASTNode::Defer(statement) => self.deferred.push((**statement).clone()),
+            // Checked at compile time
+            ASTNode::StaticAssert { .. } => {}
+            ASTNode::MultiAssign { targets, values, .. } => {
+                // Park every value on the stack first, so `a, b = b, a` reads both old values
+                for value in values {
+                    self.generate_node(value);
+                    self.emit("    push rax");
+                }
+                for target in targets.iter().rev() {
+                    self.emit("    pop rax");
+                    self.emit(&format!("    mov [{}], rax", self.slot(target)));
+                }
+            }
+            // Short-circuit: the right operand only runs when the left doesn't decide the result
+            ASTNode::BinaryOp {
+                left,
+                operator,
+                right,
+            } if operator == "&&" || operator == "||" => {
+                let and = operator == "&&";
+                let decided = self.new_label(if and { "and_false" } else { "or_true" });
+                let end_label = self.new_label("end_logic");
+                self.generate_node(left);
+                self.emit("    test rax, rax");
+                self.emit_jump(if and { "je" } else { "jne" }, &decided);
+                self.generate_node(right);
+                self.emit("    test rax, rax");
+                self.emit("    setne al");
+                self.emit("    movzx rax, al");
+                self.emit_jump("jmp", &end_label);
+                self.emit_label(&decided);
+                self.emit(&CodeGenerator::load_rax(if and { 0 } else { 1 }));
+                self.emit_label(&end_label);
             }
             ASTNode::BinaryOp {
                 left,
                 operator,
                 right,
             } => {
-                self.generate(right);
+                // Left operand first: with assignments inside expressions the order is visible
+                self.generate_node(left);
                 self.emit("    push rax");
-                self.generate(left);
-                self.emit("    pop rbx");
+                self.generate_node(right);
+                self.emit("    mov rbx, rax");
+                self.emit("    pop rax");
                 match operator.as_str() {
                     "+" => self.emit("    add rax, rbx"),
                     "-" => self.emit("    sub rax, rbx"),
                     "*" => self.emit("    imul rax, rbx"),
+                    // Signed, truncating toward zero: cqo sign-extends rax into rdx
                     "/" => {
-                        self.emit("    xor rdx, rdx");
-                        self.emit("    div rbx");
+                        self.emit("    cqo");
+                        self.emit("    idiv rbx");
+                    }
+                    "%" => {
+                        self.emit("    cqo");
+                        self.emit("    idiv rbx");
+                        self.emit("    mov rax, rdx");
+                    }
+                    "==" => {
+                        self.emit("    cmp rax, rbx");
+                        self.emit("    sete al"); // at to 1 if equal
+                        self.emit("    movzx rax, al"); // zero etend al to rax
+                    }
+                    "!=" => {
+                        self.emit("    cmp rax, rbx");
+                        self.emit("    setne al"); // at to 1 if not equal
+                        self.emit("    movzx rax, al"); // zero etend al to rax
+                    }
+                    "<" => {
+                        self.emit("    cmp rax, rbx");
+                        self.emit("    setl al"); // at to 1 if less than
+                        self.emit("    movzx rax, al"); // zero etend al to rax
+                    }
+                    ">" => {
+                        self.emit("    cmp rax, rbx");
+                        self.emit("    setg al"); // at to 1 if greater than
+                        self.emit("    movzx rax, al"); // zero etend al to rax
+                    }
+                    "<=" => {
+                        self.emit("    cmp rax, rbx");
+                        self.emit("    setle al"); // at to 1 if less than or equal
+                        self.emit("    movzx rax, al"); // zero etend al to rax
+                    }
+                    ">=" => {
+                        self.emit("    cmp rax, rbx");
+                        self.emit("    setge al"); // at to 1 if greater than or equal
+                        self.emit("    movzx rax, al"); // zero etend al to rax
+                    }
+                    _ => panic!("Unsupported operator: {}", operator),
+                }
+            }
+            ASTNode::Number(value) | ASTNode::Literal { value, .. } => {
+                self.emit(&CodeGenerator::load_rax(*value));
+            }
+            ASTNode::Folded { value, source } => {
+                let load = CodeGenerator::load_rax(*value);
+                if self.fold_comments {
+                    self.emit(&format!("{} ; folded from {}", load, source));
+                } else {
+                    self.emit(&load);
+                }
+            }
+            ASTNode::StringLiteral(_) => unreachable!("string literals are folded into printf formats"),
+            ASTNode::Variable(name, _) => {
+                self.emit(&format!("    mov rax, [{}]", self.slot(name)));
+            }
+            ASTNode::Call { name, args } => match self.builtins.get(name) {
+                Some(builtin) => (builtin.emit)(self, args),
+                None => panic!("Unsupported function: {}", name),
+            },
+            ASTNode::Print { parts, width, fd } => {
+                if let [FormatPart::Value(value)] = parts.as_slice() {
+                    self.generate_node(value);
+                    self.emit_print_rax(*fd, width.unwrap_or(0));
+                } else {
+                    self.emit_print_parts(*fd, parts);
+                    self.emit_print_text(*fd, "\n");
+                }
+            }
+            ASTNode::Printf { parts } => self.emit_print_parts(1, parts),
+            ASTNode::If {
+                condition,
+                then_branch,
+                else_branch,
+            } => {
+                self.generate_node(condition);
+                self.emit("    test rax, rax");
+                if else_branch.is_empty() {
+                    let end_label = self.new_label("end_if");
+                    self.emit_jump("je", &end_label);
+                    for stmt in then_branch {
+                        self.generate_node(stmt);
+                    }
+                    self.emit_label(&end_label);
+                } else {
+                    let else_label = self.new_label("else");
+                    let end_label = self.new_label("end_if");
+                    self.emit_jump("je", &else_label);
+                    for stmt in then_branch {
+                        self.generate_node(stmt);
+                    }
+                    if self.branch_layout {
+                        // The then-branch is taken as the likely one and falls straight through;
+                        // the else-branch moves out of line and jumps back
+                        self.emit_label(&end_label);
+                        let start = self.output.len();
+                        self.emit_label(&else_label);
+                        for stmt in else_branch {
+                            self.generate_node(stmt);
+                        }
+                        self.emit_jump("jmp", &end_label);
+                        let cold = self.output.split_off(start);
+                        self.cold.extend(cold);
+                    } else {
+                        self.emit_jump("jmp", &end_label);
+                        self.emit_label(&else_label);
+                        for stmt in else_branch {
+                            self.generate_node(stmt);
+                        }
+                        self.emit_label(&end_label);
+                    }
+                }
+            }
+            ASTNode::While { label, condition, body } => {
+                let loop_start = self.new_label("loop");
+                let loop_end = self.new_label("end_loop");
+                
+                self.emit_label(&loop_start);
+                self.generate_node(condition);
+                self.emit("    test rax, rax");
+                self.emit_jump("je", &loop_end);
+                
+                self.generate_loop_body(label, body, &loop_end, &loop_start);
+                
+                self.emit_jump("jmp", &loop_start);
+                self.emit_label(&loop_end);
+            }
+            ASTNode::DoWhile { label, body, condition } => {
+                // The body runs before the first test, so the loop always executes at least once
+                let loop_start = self.new_label("do_loop");
+                let loop_test = self.new_label("do_test");
+                let loop_end = self.new_label("end_do");
+
+                self.emit_label(&loop_start);
+                self.generate_loop_body(label, body, &loop_end, &loop_test);
+                self.emit_label(&loop_test);
+                self.generate_node(condition);
+                self.emit("    test rax, rax");
+                self.emit_jump("jne", &loop_start);
+                self.emit_label(&loop_end);
+            }
+            ASTNode::For { label, variable, range_expr, body, .. } => {
+                let loop_start = self.new_label("for_loop");
+                let loop_step = self.new_label("for_step");
+                let loop_end = self.new_label("end_for");
+                let range_end = self.new_temp("for_end");
+
+                // Generate range expression (upper bound); the body may clobber any register
+                // (print uses rbx as its divisor), so keep the bound in memory
+                self.generate_node(range_expr);
+                self.emit(&format!("    mov [{}], rax", range_end));
+                self.emit("    xor eax, eax"); // Initialize loop variable to 0
+                
+                // Loop start
+                self.emit_label(&loop_start);
+                self.emit(&format!("    cmp rax, [{}]", range_end));
+                self.emit_jump("jge", &loop_end);
+                
+                // Store current iteration value in loop variable
+                self.emit(&format!("    mov [{}], rax", self.slot(variable)));
+                
+                // Execute loop body; `continue` still goes through the increment
+                self.generate_loop_body(label, body, &loop_end, &loop_step);
+                
+                // Increment and continue
+                self.emit_label(&loop_step);
+                self.emit(&format!("    mov rax, [{}]", self.slot(variable)));
+                self.emit("    inc rax");
+                self.emit_jump("jmp", &loop_start);
+                self.emit_label(&loop_end);
+            }
+            ASTNode::Match {
+                scrutinee,
+                arms,
+                default,
+            } => {
+                // Evaluate the scrutinee once, then compare it against each arm in turn
+                let end_label = self.new_label("end_match");
+                let default_label = self.new_label("match_default");
+                let arm_labels: Vec<String> =
+                    arms.iter().map(|_| self.new_label("match_arm")).collect();
+
+                self.generate_node(scrutinee);
+                for ((value, _), label) in arms.iter().zip(&arm_labels) {
+                    if *value == 0 {
+                        self.emit("    test rax, rax");
+                    } else if i32::try_from(*value).is_ok() {
+                        self.emit(&format!("    cmp rax, {}", value));
+                    } else {
+                        self.emit(&format!("    mov rbx, {}", value));
+                        self.emit("    cmp rax, rbx");
+                    }
+                    self.emit_jump("je", label);
+                }
+                self.emit_jump("jmp", &default_label);
+
+                for ((_, body), label) in arms.iter().zip(&arm_labels) {
+                    self.emit_label(label);
+                    for stmt in body {
+                        self.generate_node(stmt);
+                    }
+                    self.emit_jump("jmp", &end_label);
+                }
+                self.emit_label(&default_label);
+                for stmt in default {
+                    self.generate_node(stmt);
+                }
+                self.emit_label(&end_label);
+            }
+            ASTNode::Sequence(items) => {
+                for item in items {
+                    self.generate_node(item);
+                }
+            }
+            ASTNode::Break(label) => {
+                let (target, _) = self.loop_targets(label);
+                self.emit_jump("jmp", &target);
+            }
+            ASTNode::Continue(label) => {
+                let (_, target) = self.loop_targets(label);
+                self.emit_jump("jmp", &target);
+            }
+        }
+    }
+
+    fn generate_loop_body(
+        &mut self,
+        label: &Option<String>,
+        body: &[ASTNode],
+        break_target: &str,
+        continue_target: &str,
+    ) {
+        self.loops.push((
+            label.clone(),
+            break_target.to_string(),
+            continue_target.to_string(),
+        ));
+        for stmt in body {
+            self.generate_node(stmt);
+        }
+        self.loops.pop();
+    }
+
+    // Break and continue targets of the innermost loop, or of the loop carrying `label`
+    fn loop_targets(&self, label: &Option<String>) -> (String, String) {
+        let found = match label {
+            Some(_) => self.loops.iter().rev().find(|(name, _, _)| name == label),
+            None => self.loops.last(),
+        };
+        match found {
+            Some((_, break_target, continue_target)) => {
+                (break_target.clone(), continue_target.clone())
+            }
+            None => panic!("break/continue outside of a matching loop"),
+        }
+    }
+}
+// Interleave each top-level statement's source (as assembly comments) with the instructions
+// generated for it. `statements` pairs each statement with the source text it came from and
+// `ranges` holds the output index range it produced.
+fn build_listing(
+    statements: &[(&str, Span)],
+    ranges: &[(usize, usize)],
+    instructions: &[String],
+) -> Vec<String> {
+    let mut listing = Vec::new();
+
+    let first = ranges.first().map_or(instructions.len(), |range| range.0);
+    listing.extend_from_slice(&instructions[..first]);
+    for ((source, span), (start, end)) in statements.iter().zip(ranges) {
+        let lines: Vec<&str> = source.lines().collect();
+        let line_count = source
+            .chars()
+            .skip(span.start)
+            .take(span.end - span.start)
+            .filter(|c| *c == '\n')
+            .count();
+        for line in span.line..=span.line + line_count {
+            listing.push(format!("; {:>4}: {}", line, lines[line - 1]));
+        }
+        listing.extend_from_slice(&instructions[*start..*end]);
+    }
+    let last = ranges.last().map_or(instructions.len(), |range| range.1);
+    listing.extend_from_slice(&instructions[last..]);
+    listing
+}
+
+struct BasicBlock {
+    // The block's label, or `bbN` (its index) for a block entered only by falling through
+    name: String,
+    instructions: Vec<String>,
+    successors: Vec<String>,
+}
+
+// Split the text section into basic blocks, which start at a label or after a jump and end at
+// a jump, `ret` or the exit call, and link each block to the blocks control can pass to next.
+// Calls are assumed to return, so `call` neither ends a block nor adds an edge.
+fn build_cfg(instructions: &[String]) -> Vec<BasicBlock> {
+    let text = instructions
+        .iter()
+        .skip_while(|line| *line != "section .text")
+        .skip(1)
+        .take_while(|line| !line.starts_with("section "));
+
+    let mut blocks: Vec<BasicBlock> = Vec::new();
+    let mut current: Option<BasicBlock> = None;
+    // Whether the last instruction of a block leaves it without falling through
+    let mut terminal = Vec::new();
+    let mut exiting = false;
+    for line in text {
+        let instruction = line.split(';').next().unwrap_or("").trim();
+        if instruction.is_empty() || instruction.starts_with("global ") || instruction.starts_with("extern ") {
+            continue;
+        }
+        if let Some(label) = instruction.strip_suffix(':') {
+            if let Some(block) = current.take() {
+                blocks.push(block);
+                terminal.push(false);
+            }
+            current = Some(BasicBlock {
+                name: label.to_string(),
+                instructions: Vec::new(),
+                successors: Vec::new(),
+            });
+            continue;
+        }
+
+        let block = current.get_or_insert_with(|| BasicBlock {
+            name: format!("bb{}", blocks.len()),
+            instructions: Vec::new(),
+            successors: Vec::new(),
+        });
+        block.instructions.push(instruction.to_string());
+        // The footer marks its exit syscall with a comment; libc mode calls exit()
+        let exits = (exiting && instruction == "syscall") || instruction == "call exit";
+        if line.contains("syscall: exit") {
+            exiting = true;
+        } else if instruction == "syscall" {
+            exiting = false;
+        }
+
+        let (op, target) = instruction.split_once(' ').unwrap_or((instruction, ""));
+        if op.starts_with('j') || op == "ret" || exits {
+            if op.starts_with('j') {
+                block.successors.push(target.to_string());
+            }
+            blocks.push(current.take().unwrap());
+            terminal.push(op == "jmp" || op == "ret" || exits);
+        }
+    }
+    if let Some(block) = current {
+        blocks.push(block);
+        terminal.push(true);
+    }
+
+    for i in 0..blocks.len().saturating_sub(1) {
+        if !terminal[i] {
+            let next = blocks[i + 1].name.clone();
+            blocks[i].successors.push(next);
+        }
+    }
+    blocks
+}
+
+struct Section {
+    name: String,
+    // Bytes reserved or initialized by data directives
+    bytes: usize,
+    // Instructions, whose encoded size is only known once the assembler has run
+    instructions: usize,
+}
+
+// Total up each section of the generated assembly, merging sections the code switches back to
+// (the footer reopens .bss and .data for temporaries and text), in order of first appearance
+fn build_layout(lines: &[String]) -> Vec<Section> {
+    // Size of one `db`/`dq`/`resb`/`resq` directive: a quoted string is one byte per character
+    fn directive_size(directive: &str, operands: &str) -> usize {
+        match directive {
+            "resb" => operands.parse().unwrap_or(0),
+            "resq" => 8 * operands.parse::<usize>().unwrap_or(0),
+            "dq" => 8 * operands.split(',').count(),
+            "db" => {
+                let mut size = 0;
+                let mut rest = operands;
+                while let Some(quote) = rest.find('"') {
+                    size += rest[..quote].split(',').filter(|item| !item.trim().is_empty()).count();
+                    let end = rest[quote + 1..].find('"').map_or(rest.len(), |end| quote + 1 + end);
+                    size += end - quote - 1;
+                    rest = &rest[(end + 1).min(rest.len())..];
+                }
+                size + rest.split(',').filter(|item| !item.trim().is_empty()).count()
+            }
+            _ => 0,
+        }
+    }
+
+    let mut sections: Vec<Section> = Vec::new();
+    let mut current: Option<usize> = None;
+    for line in lines {
+        let instruction = line.split(';').next().unwrap_or("").trim();
+        if let Some(name) = instruction.strip_prefix("section ") {
+            current = Some(match sections.iter().position(|section| section.name == name) {
+                Some(index) => index,
+                None => {
+                    sections.push(Section {
+                        name: name.to_string(),
+                        bytes: 0,
+                        instructions: 0,
+                    });
+                    sections.len() - 1
+                }
+            });
+            continue;
+        }
+        let Some(index) = current else { continue };
+        if instruction.is_empty()
+            || instruction.ends_with(':')
+            || instruction.starts_with("global ")
+            || instruction.starts_with("extern ")
+        {
+            continue;
+        }
+        // Data lines are `label directive operands`; instructions are indented
+        let mut words = instruction.splitn(3, ' ');
+        let size = match (line.starts_with(' '), words.next(), words.next(), words.next()) {
+            (false, Some(_), Some(directive), Some(operands)) => directive_size(directive, operands),
+            _ => 0,
+        };
+        if size > 0 {
+            sections[index].bytes += size;
+        } else {
+            sections[index].instructions += 1;
+        }
+    }
+    sections
+}
+
+// A JSON string literal, escaping what JSON requires
+fn json_string(text: &str) -> String {
+    let mut json = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\t' => json.push_str("\\t"),
+            c if (c as u32) < 0x20 => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+// The tokens `lexer` read as a JSON array of `{"kind", "value", "line", "column"}` objects, where
+// kind is the Token variant and value its lexeme. EOF is left out.
+fn tokens_json(lexer: &Lexer, tokens: &[(Token, Span)]) -> String {
+    let objects: Vec<String> = tokens
+        .iter()
+        .filter(|(token, _)| *token != Token::EOF)
+        .map(|(token, span)| {
+            let kind = format!("{:?}", token);
+            let kind = kind.split('(').next().unwrap_or_default();
+            let value = match token {
+                Token::Whitespace(text) | Token::Comment(text) => text.clone(),
+                _ => lexer.lexeme(*span),
+            };
+            format!(
+                "{{\"kind\": {}, \"value\": {}, \"line\": {}, \"column\": {}}}",
+                json_string(kind),
+                json_string(&value),
+                span.line,
+                span.column
+            )
+        })
+        .collect();
+    format!("[{}]", objects.join(", "))
+}
+
+// `[{"name", "assignments", "references"}]`, each span as a `{"line", "column"}` object
+fn symbols_json(index: &SymbolIndex) -> String {
+    let spans = |spans: &[Span]| {
+        let objects: Vec<String> = spans
+            .iter()
+            .map(|span| format!("{{\"line\": {}, \"column\": {}}}", span.line, span.column))
+            .collect();
+        format!("[{}]", objects.join(", "))
+    };
+    let objects: Vec<String> = index
+        .symbols
+        .iter()
+        .map(|symbol| {
+            format!(
+                "{{\"name\": {}, \"assignments\": {}, \"references\": {}}}",
+                json_string(&symbol.name),
+                spans(&symbol.assignments),
+                spans(&symbol.references)
+            )
+        })
+        .collect();
+    format!("[{}]", objects.join(", "))
+}
+
+// Counts and per-phase durations (in microseconds) as one JSON object:
+// `{"tokens", "nodes", "variables", "instructions", "durations_us": {"lex", ...}}`
+fn metrics_json(
+    tokens: usize,
+    nodes: usize,
+    variables: usize,
+    instructions: usize,
+    durations: &[(&str, Duration)],
+) -> String {
+    let durations: Vec<String> = durations
+        .iter()
+        .map(|(phase, duration)| format!("{}: {}", json_string(phase), duration.as_micros()))
+        .collect();
+    format!(
+        "{{\"tokens\": {}, \"nodes\": {}, \"variables\": {}, \"instructions\": {}, \"durations_us\": {{{}}}}}",
+        tokens,
+        nodes,
+        variables,
+        instructions,
+        durations.join(", ")
+    )
+}
+
+const USAGE: &str = "Usage: viper [options] [source.vp...]";
+
+// Longer explanations for the `[Exxx]` codes that start compile error messages, shown by
+// `viper --explain Exxx`. Codes are stable: new errors get new codes.
+const ERROR_EXPLANATIONS: &[(&str, &str)] = &[
+    (
+        "E001",
+        "A block comment was opened with `/*` but the file ended before the closing `*/`.\n\n    /* scratch\n    x = 1\n\nClose the comment with `*/`; block comments do not nest.",
+    ),
+    (
+        "E002",
+        "A number literal is malformed: it runs straight into letters (neither a number nor a name), has a `0x`/`0b` prefix with no valid digits, or misplaces a `_` separator (at the end, doubled or next to the decimal point).\n\n    x = 1abc\n    y = 0xFG\n    z = 1__000\n\nPut an operator or a space between a number and a name, and use `_` only between digits: `1_000`, `0xFF_FF`, `0b1010`.",
+    ),
+    (
+        "E003",
+        "The source contains a character that is not part of the language, such as `$`, a single `&` or `|` (the logical operators are `&&` and `||`) or a `\\` that is not the last character of its line.\n\n    x = 1 $ 2\n\nRemove the character or replace it with a supported operator.",
+    ),
+    (
+        "E004",
+        "The parser found a token where the grammar does not allow one, for example a missing parenthesis or brace, or a statement starting with something that can't start a statement.\n\n    print(1\n\nThe message names the token found and, where there is one, the token expected.",
+    ),
+    (
+        "E005",
+        "A single `=` assigns; comparisons use `==` with no space between the characters.\n\n    if x = 1 { print(x) }\n\nWrite `if x == 1` to compare. (Inside parentheses `(x = 1)` is an assignment expression.)",
+    ),
+    (
+        "E006",
+        "A call names a function that is not built in. The built-ins are `range`, `isqrt`, `gcd` and `rand`; user-defined functions are not supported yet.\n\n    y = sqrt(16)\n\nUse one of the built-ins, e.g. `isqrt(16)`.",
+    ),
+    (
+        "E007",
+        "A built-in function was called with the wrong number of arguments.\n\n    g = gcd(12)\n\n`isqrt`, `rand` and `range` take one argument and `gcd` takes two.",
+    ),
+    (
+        "E008",
+        "A `match` lists the same value in two arms, so the second arm could never run.\n\n    match x { 1 { print(1) } 1 { print(2) } }\n\nMerge the two arms or fix the value.",
+    ),
+    (
+        "E009",
+        "`break` or `continue` appears outside any loop, names a label no enclosing loop has, or a loop label reuses the label of a loop around it.\n\n    outer: while (1) { break inner }\n\nOnly enclosing loops can be named, and each needs a distinct label.",
+    ),
+    (
+        "E010",
+        "One variable is marked `global` in one assignment and `local` in another. Each variable has a single slot, so its hints must agree.\n\n    local n = 0\n    global n = 1\n\nUse one storage hint for the variable everywhere.",
+    ),
+    (
+        "E011",
+        "The generated assembly exceeded the instruction limit (1,000,000 lines by default), usually because of very long programs or heavy unrolling.\n\nRaise the limit with `--max-instructions <n>` if the program is really that large.",
+    ),
+    (
+        "E012",
+        "Under `--strict-bool`, comparisons are `Bool` and everything else is a `Number`, and the two don't mix: `if`, `while` and `do-while` conditions must be comparisons, arithmetic operands must be numbers, and a variable keeps the type of its first assignment.\n\n    if (5) { print(1) }\n    n = (a < b) + 1\n\nCompare explicitly, e.g. `if (x != 0)`, or drop `--strict-bool` to treat any nonzero value as true.",
+    ),
+    (
+        "E013",
+        "A multiple assignment needs exactly one value per target, and each target may appear only once.\n\n    a, b = 1\n    a, a = 1, 2\n\nList as many values as targets, e.g. `a, b = 1, 2`.",
+    ),
+    (
+        "E014",
+        "A string literal is missing its closing quote before the end of the line, or uses an escape that doesn't exist.\n\n    printf(\"total: %d\\q\", n)\n\nStrings end on the line they start on; the escapes are `\\n`, `\\t`, `\\\"` and `\\\\`.",
+    ),
+    (
+        "E015",
+        "A `printf` format string doesn't match its arguments: each `%d` prints one argument, so their counts must agree, and `%d` and `%%` (a literal `%`) are the only conversions.\n\n    printf(\"%d + %d\\n\", a)\n\nPass one argument per `%d`, e.g. `printf(\"%d + %d\\n\", a, b)`.",
+    ),
+    (
+        "E016",
+        "A macro definition is invalid: its name is already a built-in or an earlier macro, two parameters share a name, or the body assigns to a parameter (a parameter stands for the argument expression, which can't be assigned to).\n\n    macro gcd(a, b) = a * b\n    macro twice(x, x) = x + x\n\nPick a new name and distinct parameters, and assign to a variable that isn't a parameter.",
+    ),
+    (
+        "E017",
+        "`defer` schedules a statement to run when the program ends, so it is only allowed at the top level, not inside a block or another `defer`.\n\n    while (i < 3) {\n        defer print(i)\n    }\n\nMove the `defer` out of the block.",
+    ),
+    (
+        "E018",
+        "A `static_assert` condition is checked at compile time: it must fold to a constant, using the values of variables assigned constants earlier in straight-line code, and that constant must be nonzero (true).\n\n    size = 4\n    static_assert(size > 8)\n    static_assert(rand(2) == 0)\n\nFix the constant the assertion guards, or use an `if` to check values only known at run time.",
+    ),
+    (
+        "E019",
+        "The `width` of a `print` must be a whole number from 1 to 20, written as a literal: the number is right-aligned in that many columns, and 20 is the most digits a 64-bit number has. It applies to a print of a single number, not to one of several values or strings.\n\n    print(x, width=0)\n    print(x, width=n)\n    print(\"x = \", x, width=5)\n\nUse a constant width such as `print(x, width=5)`.",
+    ),
+    (
+        "E020",
+        "Only a variable can be on the left of `=`; a number or the result of an expression has nowhere to store the value.\n\n    5 = x\n    (a + 1) = 5\n\nPut the variable on the left (`x = 5`), or assign the result to a new variable (`b = a + 1`).",
+    ),
+    (
+        "E021",
+        "The `#if` directives are unbalanced or malformed. `#if NAME` takes one symbol, may be followed by one `#else`, and must be closed by an `#endif`.\n\n    #if DEBUG verbose\n    print(x)\n\nName a single symbol and close the block:\n\n    #if DEBUG\n    print(x)\n    #endif",
+    ),
+    (
+        "E022",
+        "The source is not valid UTF-8, e.g. a binary file or text saved in another encoding such as Latin-1. The message gives the line and byte offset of the first invalid byte.\n\nSave the file as UTF-8, or check that the right file was passed.",
+    ),
+];
+
+// Linker for macos - x86_64-elf-ld; fallback onto gnu linker on linux
+const LINKER: &str = if cfg!(target_os = "macos") {
+    "x86_64-elf-ld"
+} else {
+    "ld"
+};
+
+// Program and flags that link an object file. With libc the C compiler driver links it, adding
+// libc and the startup code that calls `main`; no PIE, as the generated code uses absolute
+// addresses.
+fn link_command(use_libc: bool, target: TargetOs) -> (&'static str, &'static [&'static str]) {
+    match target {
+        // Apple's ld needs the SDK to link even a static-looking binary; the compiler driver
+        // knows where it is
+        TargetOs::MacOs => ("cc", &[]),
+        TargetOs::Linux if use_libc => ("cc", &["-no-pie"]),
+        TargetOs::Linux => (LINKER, &[]),
+    }
+}
+
+struct Options {
+    inputs: Vec<String>,
+    // Name diagnostics use for the input `-` (standard input)
+    stdin_name: String,
+    output: String,
+    dump_tokens: bool,
+    run: bool,
+    keep_asm: bool,
+    tab_width: usize,
+    optimize: bool,
+    dry_run: bool,
+    listing: bool,
+    format: bool,
+    profile: bool,
+    max_instructions: usize,
+    max_unroll: usize,
+    seed: Option<u64>,
+    emit_makefile: bool,
+    use_libc: bool,
+    color: bool,
+    target_os: TargetOs,
+    emit: Option<Emit>,
+    explain: Option<String>,
+    strict_bool: bool,
+    case_insensitive_keywords: bool,
+    safe_div: bool,
+    annotate_folds: bool,
+    runtime_base_toggle: bool,
+    // Also lex whitespace and comments as tokens for --dump-tokens and --emit=tokens-json
+    trivia: bool,
+    // Symbols given with --define, tested by `#if NAME`
+    defines: HashSet<String>,
+    precedence: HashMap<String, (u8, Associativity)>,
+    werror: bool,
+    out_dir: Option<String>,
+    force: bool,
+}
+
+// Alternative outputs selected with --emit=<kind>, printed instead of writing assembly
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Emit {
+    Cfg,
+    Layout,
+    TokensJson,
+    AstRust,
+    MetricsJson,
+    SymbolsJson,
+}
+
+fn usage_error(message: &str) -> ! {
+    eprintln!("{}", message);
+    eprintln!("{}", USAGE);
+    process::exit(1);
+}
+
+// Print `severity: [location: ]message` to stderr. With color the severity is bold red (error)
+// or yellow (warning) and the location is dimmed.
+fn diagnostic(color: bool, severity: &str, location: Option<&str>, message: &str) {
+    let paint = |code: &str, text: &str| {
+        if color {
+            format!("\x1b[{}m{}\x1b[0m", code, text)
+        } else {
+            text.to_string()
+        }
+    };
+    let severity_color = if severity == "error" { "1;31" } else { "1;33" };
+    let location = location.map_or(String::new(), |location| paint("2", &format!("{}:", location)) + " ");
+    eprintln!("{} {}{}", paint(severity_color, &format!("{}:", severity)), location, message);
+}
+
+// Report a compile error in the input called `name` and exit
+fn compile_error_in(color: bool, name: &str, error: CompileError) -> ! {
+    diagnostic(color, "error", Some(name), &error.to_string());
+    process::exit(1);
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            inputs: Vec::new(),
+            stdin_name: "<stdin>".to_string(),
+            output: "output.asm".to_string(),
+            dump_tokens: false,
+            run: false,
+            keep_asm: false,
+            tab_width: 1,
+            optimize: false,
+            dry_run: false,
+            listing: false,
+            format: false,
+            profile: false,
+            max_instructions: DEFAULT_INSTRUCTION_LIMIT,
+            max_unroll: DEFAULT_MAX_UNROLL,
+            seed: None,
+            emit_makefile: false,
+            use_libc: false,
+            color: io::stderr().is_terminal(),
+            target_os: TargetOs::Linux,
+            emit: None,
+            explain: None,
+            strict_bool: false,
+            case_insensitive_keywords: false,
+            safe_div: false,
+            annotate_folds: false,
+            runtime_base_toggle: false,
+            trivia: false,
+            defines: HashSet::new(),
+            precedence: default_precedence(),
+            werror: false,
+            out_dir: None,
+            force: false,
+        }
+    }
+}
+
+impl Options {
+    // How diagnostics refer to an input: its path, or --stdin-name for `-`
+    fn input_name<'a>(&'a self, path: &'a str) -> &'a str {
+        if path == "-" {
+            &self.stdin_name
+        } else {
+            path
+        }
+    }
+
+    // A parser for these options reading from `lexer`. --format reprints the program as written,
+    // so it skips the checks and rewrites of --strict-bool and --safe-div.
+    fn parser(&self, lexer: Lexer) -> Parser {
+        // compile_program hands the code generator the same builtins
+        let mut parser = Parser::new(lexer)
+            .with_builtins(default_builtins())
+            .with_precedence(self.precedence.clone());
+        if self.strict_bool && !self.format {
+            parser = parser.with_strict_bool();
+        }
+        if self.safe_div && !self.format {
+            parser = parser.with_safe_div();
+        }
+        parser
+    }
+
+    fn from_args() -> Self {
+        let mut options = Options::default();
+        let mut explicit_output = false;
+
+        let mut args = env::args().skip(1);
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--dump-tokens" => options.dump_tokens = true,
+                "--trivia" => options.trivia = true,
+                "--run" => options.run = true,
+                "--keep-asm" => options.keep_asm = true,
+                "-O" => options.optimize = true,
+                "--dry-run" => options.dry_run = true,
+                "--listing" => options.listing = true,
+                "--format" => options.format = true,
+                "--emit-makefile" => options.emit_makefile = true,
+                "--use-libc" => options.use_libc = true,
+                "--profile" => options.profile = true,
+                "--strict-bool" => options.strict_bool = true,
+                "--safe-div" => options.safe_div = true,
+                "--annotate-folds" => options.annotate_folds = true,
+                "--runtime-base-toggle" => options.runtime_base_toggle = true,
+                "--case-insensitive-keywords" => options.case_insensitive_keywords = true,
+                "--werror" => options.werror = true,
+                "--force" => options.force = true,
+                "--tab-width" => {
+                    options.tab_width = match args.next().map(|value| value.parse()) {
+                        Some(Ok(width)) if width > 0 => width,
+                        _ => usage_error("--tab-width expects a positive number"),
                     }
-                    "==" => {
-                        self.emit("    cmp rax, rbx");
-                        self.emit("    sete al"); // at to 1 if equal
-                        self.emit("    movzx rax, al"); // zero etend al to rax
+                }
+                // `OP=LEVEL` or `OP=LEVEL:ASSOCIATIVITY`, overriding one operator's entry
+                "--precedence" => {
+                    let value = args.next().unwrap_or_default();
+                    // The last `=`, since `<=`, `>=`, `==` and `!=` contain one
+                    let entry = value.rsplit_once('=').and_then(|(operator, rule)| {
+                        let current = options.precedence.get(operator)?;
+                        let (level, associativity) = rule.split_once(':').unwrap_or((rule, ""));
+                        let level = level.parse().ok().filter(|level| *level > 0)?;
+                        let associativity = match associativity {
+                            "" => current.1,
+                            "left" => Associativity::Left,
+                            "right" => Associativity::Right,
+                            "none" => Associativity::None,
+                            _ => return None,
+                        };
+                        Some((operator.to_string(), (level, associativity)))
+                    });
+                    match entry {
+                        Some((operator, rule)) => {
+                            options.precedence.insert(operator, rule);
+                        }
+                        None => usage_error(
+                            "--precedence expects OP=LEVEL[:left|right|none] for a binary operator",
+                        ),
                     }
-                    "!=" => {
-                        self.emit("    cmp rax, rbx");
-                        self.emit("    setne al"); // at to 1 if not equal
-                        self.emit("    movzx rax, al"); // zero etend al to rax
+                }
+                "--max-instructions" => {
+                    options.max_instructions = match args.next().map(|value| value.parse()) {
+                        Some(Ok(limit)) if limit > 0 => limit,
+                        _ => usage_error("--max-instructions expects a positive number"),
                     }
-                    "<" => {
-                        self.emit("    cmp rax, rbx");
-                        self.emit("    setl al"); // at to 1 if less than
-                        self.emit("    movzx rax, al"); // zero etend al to rax
+                }
+                "--max-unroll" => {
+                    options.max_unroll = match args.next().map(|value| value.parse()) {
+                        Some(Ok(limit)) => limit,
+                        _ => usage_error("--max-unroll expects a non-negative number"),
                     }
-                    ">" => {
-                        self.emit("    cmp rax, rbx");
-                        self.emit("    setg al"); // at to 1 if greater than
-                        self.emit("    movzx rax, al"); // zero etend al to rax
+                }
+                "--define" => match args.next() {
+                    Some(name) if is_identifier(&name) => {
+                        options.defines.insert(name);
                     }
-                    "<=" => {
-                        self.emit("    cmp rax, rbx");
-                        self.emit("    setle al"); // at to 1 if less than or equal
-                        self.emit("    movzx rax, al"); // zero etend al to rax
+                    _ => usage_error("--define expects a symbol name such as DEBUG"),
+                },
+                "--seed" => {
+                    options.seed = match args.next().map(|value| value.parse()) {
+                        Some(Ok(seed)) => Some(seed),
+                        _ => usage_error("--seed expects a non-negative integer"),
                     }
-                    ">=" => {
-                        self.emit("    cmp rax, rbx");
-                        self.emit("    setge al"); // at to 1 if greater than or equal
-                        self.emit("    movzx rax, al"); // zero etend al to rax
+                }
+                "--explain" => match args.next() {
+                    Some(code) => options.explain = Some(code),
+                    None => usage_error("--explain expects an error code such as E001"),
+                },
+                "-o" => match args.next() {
+                    Some(path) => {
+                        options.output = path;
+                        explicit_output = true;
+                    }
+                    None => usage_error("-o expects an output path"),
+                },
+                "--stdin-name" => match args.next() {
+                    Some(name) => options.stdin_name = name,
+                    None => usage_error("--stdin-name expects a name"),
+                },
+                "--out-dir" => match args.next() {
+                    Some(dir) => options.out_dir = Some(dir),
+                    None => usage_error("--out-dir expects a directory"),
+                },
+                flag if flag.starts_with("--color=") => {
+                    options.color = match &flag["--color=".len()..] {
+                        "auto" => io::stderr().is_terminal(),
+                        "always" => true,
+                        "never" => false,
+                        _ => usage_error("--color expects auto, always or never"),
                     }
-                    _ => panic!("Unsupported operator: {}", operator),
                 }
+                flag if flag.starts_with("--target-os=") => {
+                    options.target_os = match &flag["--target-os=".len()..] {
+                        "linux" => TargetOs::Linux,
+                        "macos" => TargetOs::MacOs,
+                        _ => usage_error("--target-os expects linux or macos"),
+                    }
+                }
+                flag if flag.starts_with("--emit=") => {
+                    options.emit = match &flag["--emit=".len()..] {
+                        "cfg" => Some(Emit::Cfg),
+                        "layout" => Some(Emit::Layout),
+                        "tokens-json" => Some(Emit::TokensJson),
+                        "ast-rust" => Some(Emit::AstRust),
+                        "metrics-json" => Some(Emit::MetricsJson),
+                        "symbols-json" => Some(Emit::SymbolsJson),
+                        _ => usage_error(
+                            "--emit expects cfg, layout, tokens-json, ast-rust, metrics-json \
+                             or symbols-json",
+                        ),
+                    }
+                }
+                "-" if options.inputs.iter().any(|input| input == "-") => {
+                    usage_error("standard input (-) can only be read once")
+                }
+                "-" => options.inputs.push("-".to_string()),
+                flag if flag.starts_with('-') => usage_error(&format!("Unknown option: {}", flag)),
+                path => options.inputs.push(path.to_string()),
             }
-            ASTNode::Number(value) => {
-                self.emit(&format!("    mov rax, {}", *value as i64));
+        }
+
+        if options.inputs.is_empty() {
+            options.inputs.push("example.vp".to_string());
+        }
+        if let Some(dir) = &options.out_dir {
+            if explicit_output {
+                usage_error("-o can't be combined with --out-dir");
             }
-            ASTNode::Variable(name) => {
-                self.emit(&format!("    mov rax, [{}]", name));
+            options.output = derive_output_path(options.input_name(&options.inputs[0]), dir);
+        }
+
+        // The listing pairs source statements with their code, which -O is free to rearrange
+        if options.listing && options.optimize {
+            usage_error("--listing can't be combined with -O");
+        }
+        // Symbols from libc carry a leading underscore on macOS, which the libc mode doesn't emit
+        if options.use_libc && options.target_os == TargetOs::MacOs {
+            usage_error("--use-libc is only supported with --target-os=linux");
+        }
+
+        options
+    }
+}
+
+// `<dir>/<input name>.asm`: the input's directory is dropped and its extension (`.vp`) replaced
+fn derive_output_path(input: &str, dir: &str) -> String {
+    let name = Path::new(input).with_extension("asm");
+    let name = name.file_name().expect("input paths name a file");
+    Path::new(dir).join(name).to_string_lossy().into_owned()
+}
+
+// Read a source file (or standard input, for `-`) as raw bytes and decode it, so a file that
+// isn't UTF-8 (a binary, or text in another encoding) is reported with the offending offset
+// rather than as a bare I/O error. `name` is how messages refer to the input.
+fn read_source(path: &str, name: &str) -> Result<String, String> {
+    let bytes = if path == "-" {
+        let mut bytes = Vec::new();
+        io::stdin().read_to_end(&mut bytes).map(|_| bytes)
+    } else {
+        fs::read(path)
+    };
+    let bytes = bytes.map_err(|e| format!("cannot read {}: {}", name, e))?;
+    String::from_utf8(bytes).map_err(|e| {
+        let error = CompileError::invalid_utf8(e.as_bytes(), e.utf8_error().valid_up_to());
+        format!("{}: {}", name, error)
+    })
+}
+
+// Compile a program given as raw bytes with the default options. Bytes that aren't UTF-8 fail
+// with InvalidUtf8 rather than a generic error.
+#[cfg(test)]
+fn compile_bytes(source: &[u8]) -> Result<String, CompileError> {
+    let source = std::str::from_utf8(source)
+        .map_err(|e| CompileError::invalid_utf8(source, e.valid_up_to()))?;
+    compile(source)
+}
+
+// Compile a program with the default options and return its assembly, through the same
+// compile_program as the command line. There is no library target yet, so this is for the tests.
+#[cfg(test)]
+fn compile(source: &str) -> Result<String, CompileError> {
+    let options = Options::default();
+    let source = catch_compile_error(|| preprocess(source, &options.defines))?;
+    let mut parser = options.parser(Lexer::new(""));
+    let program = compile_program(&mut parser, &[("<source>", &source)], &options, &mut |_, _| {})
+        .map_err(|(_, error)| error)?;
+    Ok(program.codegen.output.join("\n") + "\n")
+}
+
+// A program compiled from its inputs, with what the command line reports about it
+struct Program<'a> {
+    // The statements of every input in order, after -O when given
+    ast: Vec<ASTNode>,
+    // The input and span of each statement, and the range of output lines generated for it
+    statements: Vec<(&'a str, Span)>,
+    ranges: Vec<(usize, usize)>,
+    variables: Vec<String>,
+    codegen: CodeGenerator,
+    parse_time: Duration,
+    optimize_time: Duration,
+    codegen_time: Duration,
+}
+
+// Compile the inputs, each a name and its preprocessed source, into one program: every input is
+// parsed separately with `parser`, its macros expanded, unreachable statements removed and static
+// asserts checked, then -O is applied and code is generated. Warnings go to `warn` with their
+// location; an error comes back with the name of the input it was found in, if any.
+fn compile_program<'a>(
+    parser: &mut Parser,
+    inputs: &[(&'a str, &'a str)],
+    options: &Options,
+    warn: &mut dyn FnMut(Option<&str>, &str),
+) -> Result<Program<'a>, (Option<&'a str>, CompileError)> {
+    // The program is the statements of the inputs in order. Variables are global, so one
+    // assigned in several inputs is reported.
+    let started = Instant::now();
+    let mut ast = Vec::new();
+    let mut statements = Vec::new();
+    let mut assigned_in: Vec<(String, &str)> = Vec::new();
+    let mut storage_in: Vec<(String, Storage, &str)> = Vec::new();
+    // Where each variable is first assigned, and every variable read anywhere
+    let mut first_assigned: Vec<(String, String)> = Vec::new();
+    let mut read = HashSet::new();
+    // The constants static_assert sees, carried from one input to the next
+    let mut constants = HashMap::new();
+    for &(path, source_code) in inputs {
+        parser.reset(source_code);
+        let mut macros = HashMap::new();
+        let (symbols, nodes) = catch_compile_error(|| {
+            let parsed = parser.parse();
+            let symbols = build_symbol_index(&parsed);
+            let nodes: Vec<ASTNode> = parsed
+                .into_iter()
+                .map(|node| node.expand_macros(&mut macros))
+                .collect();
+            (symbols, nodes)
+        })
+        .map_err(|error| (Some(path), error))?;
+
+        let mut file_variables = Vec::new();
+        for node in &nodes {
+            ASTNode::collect_variables(node, &mut file_variables);
+        }
+        for variable in file_variables {
+            match assigned_in.iter().find(|(name, _)| *name == variable) {
+                Some((_, other)) => warn(
+                    None,
+                    &format!("variable '{}' is assigned in both {} and {}", variable, other, path),
+                ),
+                None => assigned_in.push((variable, path)),
             }
-            ASTNode::Print { expression } => {
-                self.generate(expression);
-                self.emit("    mov rcx, buffer");
-                self.emit("    call int_to_string");
-                self.emit("    mov rdx, buffer");
-                self.emit("    add rdx, 20");
-                self.emit("    sub rdx, rcx");
-                self.emit("    mov rsi, rcx");
-                self.emit("    mov rax, 1");
-                self.emit("    mov rdi, 1");
-                self.emit("    syscall");
+        }
 
-                self.emit("    mov rsi, newline");
-                self.emit("    mov rdx, 1");
-                self.emit("    mov rax, 1");
-                self.emit("    mov rdi, 1");
-                self.emit("    syscall");
+        for symbol in symbols.symbols {
+            if !symbol.references.is_empty() {
+                read.insert(symbol.name.clone());
             }
-            ASTNode::If {
-                condition,
-                then_branch,
-                else_branch,
-            } => {
-                self.generate(condition);
-                self.emit("    cmp rax, 0");
-                let else_label = self.new_label("else");
-                let end_label = self.new_label("end_if");
-                self.emit(&format!("    je {}", else_label));
-                for stmt in then_branch {
-                    self.generate(stmt);
-                }
-                self.emit(&format!("    jmp {}", end_label));
-                self.emit(&format!("{}:", else_label));
-                for stmt in else_branch {
-                    self.generate(stmt);
-                }
-                self.emit(&format!("{}:", end_label));
-            }
-            ASTNode::While { condition, body } => {
-                let loop_start = self.new_label("loop");
-                let loop_end = self.new_label("end_loop");
-                
-                self.emit(&format!("{}:", loop_start));
-                self.generate(condition);
-                self.emit("    cmp rax, 0");
-                self.emit(&format!("    je {}", loop_end));
-                
-                for stmt in body {
-                    self.generate(stmt);
+            if let Some(span) = symbol.assignments.first() {
+                if !first_assigned.iter().any(|(first, _)| *first == symbol.name) {
+                    first_assigned.push((symbol.name, format!("{}:{}", path, span)));
                 }
-                
-                self.emit(&format!("    jmp {}", loop_start));
-                self.emit(&format!("{}:", loop_end));
             }
-            ASTNode::For { variable, range_expr, body } => {
-                let loop_start = self.new_label("for_loop");
-                let loop_end = self.new_label("end_for");
+        }
 
-                // Generate range expression (upper bound)
-                self.generate(range_expr);
-                self.emit("    mov rbx, rax"); // Store range end in rbx
-                self.emit("    mov rax, 0"); // Initialize loop variable to 0
-                
-                // Loop start
-                self.emit(&format!("{}:", loop_start));
-                self.emit("    cmp rax, rbx");
-                self.emit(&format!("    jge {}", loop_end));
-                
-                // Store current iteration value in loop variable
-                self.emit(&format!("    mov [{}], rax", variable));
-                
-                // Execute loop body
-                for stmt in body {
-                    self.generate(stmt);
+        let mut hints: Vec<(&String, &(Storage, Span))> = parser.storage_hints.iter().collect();
+        hints.sort_by_key(|(_, (_, span))| span.start);
+        for (variable, (storage, span)) in hints {
+            match storage_in.iter().find(|(name, _, _)| name == variable) {
+                Some((_, other, other_path)) if other != storage => {
+                    let error = CompileError::ConflictingStorage(format!(
+                        "Variable '{}' is declared {} at {} but {} in {}",
+                        variable,
+                        storage.keyword(),
+                        span,
+                        other.keyword(),
+                        other_path
+                    ));
+                    return Err((Some(path), error));
                 }
-                
-                // Increment and continue
-                self.emit(&format!("    mov rax, [{}]", variable));
-                self.emit("    inc rax");
-                self.emit(&format!("    jmp {}", loop_start));
-                self.emit(&format!("{}:", loop_end));
+                Some(_) => {}
+                None => storage_in.push((variable.clone(), *storage, path)),
             }
-            ASTNode::Break => {
-                self.emit("    jmp .break_target");
-            }
-            ASTNode::Continue => {
-                self.emit("    jmp .continue_target");
+        }
+
+        let nodes: Vec<ASTNode> = nodes
+            .into_iter()
+            .zip(&parser.statement_spans)
+            .map(|(node, span)| {
+                let mut removed = 0;
+                let node = Optimizer::remove_unreachable(node, &mut removed);
+                if removed > 0 {
+                    // Point at the first statement dropped from this one
+                    let first = parser
+                        .unreachable_spans
+                        .iter()
+                        .find(|dead| span.start <= dead.start && dead.start < span.end)
+                        .unwrap_or(span);
+                    warn(
+                        Some(&format!("{}:{}", path, first)),
+                        &format!(
+                            "{} statement(s) after 'break'/'continue' can never run and were removed",
+                            removed
+                        ),
+                    );
+                }
+                node
+            })
+            .collect();
+
+        for (node, span) in nodes.iter().zip(&parser.statement_spans) {
+            let mut infinite = Vec::new();
+            Optimizer::infinite_loops(std::slice::from_ref(node), &mut infinite);
+            for keyword in infinite {
+                warn(
+                    Some(&format!("{}:{}", path, span)),
+                    &format!(
+                        "{} loop never terminates: its condition is always true and nothing breaks out of it",
+                        keyword
+                    ),
+                );
             }
         }
+
+        catch_compile_error(|| Optimizer::check_static_asserts(&nodes, &mut constants))
+            .map_err(|error| (Some(path), error))?;
+
+        statements.extend(parser.statement_spans.iter().map(|span| (source_code, *span)));
+        ast.extend(nodes);
     }
-}
-fn main() {
-    let source_path = "example.vp";
-    let source_code = fs::read_to_string(source_path).unwrap();
+    // A leading `_` marks a variable as deliberately unused, e.g. a loop counter
+    for (variable, location) in &first_assigned {
+        if !read.contains(variable) && !variable.starts_with('_') {
+            warn(
+                Some(location),
+                &format!("variable '{}' is assigned but never read", variable),
+            );
+        }
+    }
+    let parse_time = started.elapsed();
 
-    let lexer = Lexer::new(&source_code);
-    let mut parser = Parser::new(lexer);
-    let ast = parser.parse();
+    let started = Instant::now();
+    let ast = if options.optimize {
+        catch_compile_error(|| Optimizer::new().with_max_unroll(options.max_unroll).optimize(ast))
+            .map_err(|error| (None, error))?
+    } else {
+        ast
+    };
+    let optimize_time = started.elapsed();
 
     let mut variables = Vec::new();
     for node in &ast {
         ASTNode::collect_variables(node, &mut variables);
     }
 
-    let mut codegen = CodeGenerator::new("output.asm");
-    codegen.emit_header(&variables);
-    for node in ast {
-        codegen.generate(&node);
+    // The parser and the code generator must agree on the functions programs can call
+    let started = Instant::now();
+    let mut codegen = CodeGenerator::new()
+        .with_builtins(parser.builtins.clone())
+        .with_branch_layout(options.optimize)
+        .with_fold_comments(options.annotate_folds)
+        .with_profiling(options.profile)
+        .with_base_toggle(options.runtime_base_toggle)
+        .with_instruction_limit(options.max_instructions)
+        .with_seed(options.seed)
+        .with_libc(options.use_libc)
+        .with_target(options.target_os);
+    let locals: Vec<String> = storage_in
+        .into_iter()
+        .filter(|(_, storage, _)| *storage == Storage::Local)
+        .map(|(variable, _, _)| variable)
+        .collect();
+    let ranges = catch_compile_error(|| {
+        codegen.emit_header(&variables, &locals);
+        let mut ranges = Vec::new();
+        for node in &ast {
+            let start = codegen.output.len();
+            codegen.generate(node)?;
+            ranges.push((start, codegen.output.len()));
+        }
+        codegen.emit_footer()?;
+        if options.optimize {
+            codegen.thread_jumps();
+        }
+        Ok(ranges)
+    })
+    .and_then(|ranges| ranges)
+    .map_err(|error| (None, error))?;
+    let codegen_time = started.elapsed();
+
+    Ok(Program {
+        ast,
+        statements,
+        ranges,
+        variables,
+        codegen,
+        parse_time,
+        optimize_time,
+        codegen_time,
+    })
+}
+
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars.next().is_some_and(Lexer::is_ident_start) && chars.all(Lexer::is_ident_continue)
+}
+
+// Apply the `#if NAME` / `#else` / `#endif` directives, which may nest: the lines of a branch
+// not taken are blanked out, keeping their line breaks so positions in the rest of the source
+// don't move. The directive lines stay as they are, since to the lexer they are `#` comments.
+// Only `#if`, `#else` and `#endif` followed by a space or the end of the line are directives.
+fn preprocess(source: &str, defines: &HashSet<String>) -> String {
+    // For each open `#if`: whether its current branch is kept, whether it reached `#else`, and
+    // where it is
+    let mut open: Vec<(bool, bool, String)> = Vec::new();
+    let mut output = String::with_capacity(source.len());
+    for (index, line) in source.split_inclusive('\n').enumerate() {
+        let indent = line.chars().take_while(|c| c.is_whitespace()).count();
+        let at = format!("{}:{}", index + 1, indent + 1);
+        let kept = open.iter().all(|(taken, _, _)| *taken);
+        let mut words = line.split_whitespace();
+        match words.next() {
+            Some("#if") => {
+                let name = words.next().filter(|name| is_identifier(name));
+                let (Some(name), None) = (name, words.next()) else {
+                    fail(CompileError::UnbalancedDirective(format!(
+                        "`#if` expects a single symbol name at {}",
+                        at
+                    )));
+                };
+                open.push((defines.contains(name), false, at));
+            }
+            Some("#else") => match open.last_mut() {
+                Some((_, true, _)) => {
+                    fail(CompileError::UnbalancedDirective(format!(
+                        "Second `#else` for the same `#if` at {}",
+                        at
+                    )))
+                }
+                Some((taken, seen_else, _)) => {
+                    *taken = !*taken;
+                    *seen_else = true;
+                }
+                None => {
+                    fail(CompileError::UnbalancedDirective(format!(
+                        "`#else` without an `#if` at {}",
+                        at
+                    )))
+                }
+            },
+            Some("#endif") if open.is_empty() => {
+                fail(CompileError::UnbalancedDirective(format!(
+                    "`#endif` without an `#if` at {}",
+                    at
+                )))
+            }
+            Some("#endif") => {
+                open.pop();
+            }
+            _ if !kept => {
+                output.push_str(&line[line.trim_end_matches(['\r', '\n']).len()..]);
+                continue;
+            }
+            _ => {}
+        }
+        output.push_str(line);
+    }
+    if let Some((_, _, at)) = open.last() {
+        fail(CompileError::UnbalancedDirective(format!(
+            "`#if` at {} is never closed with `#endif`",
+            at
+        )));
+    }
+    output
+}
+
+fn run_tool(program: &str, args: &[&str]) -> Result<(), String> {
+    let status = Command::new(program)
+        .args(args)
+        .status()
+        .map_err(|e| format!("Unable to run {}: {}", program, e))?;
+    if !status.success() {
+        return Err(format!("{} failed with {}", program, status));
+    }
+    Ok(())
+}
+
+// Makefile with the same nasm/ld steps --run uses, so the assembly can be built by hand with
+// `make -f <stem>.mk`. Paths are as given on the command line, i.e. relative to where viper ran.
+fn build_makefile(asm_path: &str, use_libc: bool, target: TargetOs) -> String {
+    let object = Path::new(asm_path).with_extension("o");
+    let binary = Path::new(asm_path).with_extension("");
+    let (object, binary) = (object.to_string_lossy(), binary.to_string_lossy());
+    let (linker, flags) = link_command(use_libc, target);
+    let linker = [linker].iter().chain(flags).copied().collect::<Vec<_>>().join(" ");
+    [
+        format!("# Generated by viper from {}", asm_path),
+        format!("{}: {}", binary, object),
+        format!("\t{} {} -o {}", linker, object, binary),
+        String::new(),
+        format!("{}: {}", object, asm_path),
+        format!("\tnasm -f {} {} -o {}", target.object_format(), asm_path, object),
+        String::new(),
+        ".PHONY: clean".to_string(),
+        "clean:".to_string(),
+        format!("\trm -f {} {}", object, binary),
+    ]
+    .join("\n")
+        + "\n"
+}
+
+// Assemble, link and execute the generated program, returning its stdout and exit code.
+// The object file and binary sit next to the assembly and share its stem.
+fn build_and_run(
+    asm_path: &str,
+    keep_files: bool,
+    use_libc: bool,
+    target: TargetOs,
+) -> Result<(String, i32), String> {
+    let object_path = Path::new(asm_path).with_extension("o");
+    let binary_path = Path::new(".").join(Path::new(asm_path).with_extension(""));
+    let object = object_path.to_string_lossy();
+    let binary = binary_path.to_string_lossy();
+
+    let (linker, flags) = link_command(use_libc, target);
+    let link_args: Vec<&str> = flags.iter().copied().chain([&*object, "-o", &*binary]).collect();
+
+    let result = run_tool("nasm", &["-f", target.object_format(), asm_path, "-o", &object])
+        .and_then(|_| run_tool(linker, &link_args))
+        .and_then(|_| {
+            Command::new(&binary_path)
+                .stderr(Stdio::inherit())
+                .output()
+                .map_err(|e| format!("Unable to run {}: {}", binary, e))
+        })
+        .map(|output| {
+            (
+                String::from_utf8_lossy(&output.stdout).into_owned(),
+                output.status.code().unwrap_or(-1),
+            )
+        });
+
+    if !keep_files {
+        for path in [Path::new(asm_path), &object_path, &binary_path] {
+            let _ = fs::remove_file(path);
+        }
+    }
+    result
+}
+
+fn main() {
+    let options = Options::from_args();
+    if let Err(error) = catch_compile_error(|| run(&options)) {
+        diagnostic(options.color, "error", None, &error.to_string());
+        process::exit(1);
+    }
+}
+
+fn run(options: &Options) {
+    if let Some(code) = &options.explain {
+        match ERROR_EXPLANATIONS.iter().find(|(known, _)| known.eq_ignore_ascii_case(code)) {
+            Some((known, explanation)) => println!("{}: {}", known, explanation),
+            None => usage_error(&format!("Unknown error code: {}", code)),
+        }
+        return;
+    }
+    let sources: Vec<String> = options
+        .inputs
+        .iter()
+        .map(|path| {
+            read_source(path, options.input_name(path)).unwrap_or_else(|message| {
+                diagnostic(options.color, "error", None, &message);
+                process::exit(1);
+            })
+        })
+        .collect();
+
+    let names: Vec<&str> = options.inputs.iter().map(|path| options.input_name(path)).collect();
+    let sources: Vec<String> = sources
+        .iter()
+        .zip(&names)
+        .map(|(source, name)| {
+            catch_compile_error(|| preprocess(source, &options.defines))
+                .unwrap_or_else(|error| compile_error_in(options.color, name, error))
+        })
+        .collect();
+
+    let lexer_options = LexerOptions {
+        tab_width: options.tab_width,
+        case_insensitive_keywords: options.case_insensitive_keywords,
+        preserve_trivia: options.trivia,
+    };
+
+    // One lexer and one parser serve every input, each reset to the next file
+    let mut lexer = Lexer::new("").with_options(lexer_options);
+    if options.dump_tokens {
+        for (path, source_code) in names.iter().zip(&sources) {
+            if names.len() > 1 {
+                println!("# {}", path);
+            }
+            lexer.reset(source_code);
+            for (token, span) in lexer.tokenize_with_spans() {
+                println!("{}\t{}..{}\t{:?}", span, span.start, span.end, token);
+            }
+        }
+        return;
+    }
+    if options.emit == Some(Emit::TokensJson) {
+        // One array per input, each on its own line
+        for source_code in &sources {
+            lexer.reset(source_code);
+            let tokens = lexer.tokenize_with_spans();
+            println!("{}", tokens_json(&lexer, &tokens));
+        }
+        return;
+    }
+
+    // For --emit=metrics-json, lex on its own to count tokens; the parser lexes again as it goes
+    let started = Instant::now();
+    let mut token_count = 0;
+    if options.emit == Some(Emit::MetricsJson) {
+        for source_code in &sources {
+            lexer.reset(source_code);
+            let tokens = lexer.tokenize_with_spans();
+            token_count += tokens.iter().filter(|(token, _)| !token.is_trivia()).count() - 1; // without EOF
+        }
+    }
+    let lex_time = started.elapsed();
+
+    let mut parser = options.parser(lexer);
+
+    if options.format {
+        for source_code in &sources {
+            parser.reset(source_code);
+            print!("{}", Formatter::format(&parser.parse()));
+        }
+        return;
+    }
+    if options.emit == Some(Emit::SymbolsJson) {
+        // One array per input, like --emit=tokens-json
+        for source_code in &sources {
+            parser.reset(source_code);
+            println!("{}", symbols_json(&build_symbol_index(&parser.parse())));
+        }
+        return;
+    }
+
+    // With --werror every warning is reported as an error and compilation stops once the
+    // program has been checked
+    let mut warnings = 0;
+    let mut warn = |location: Option<&str>, message: &str| {
+        warnings += 1;
+        if options.werror {
+            diagnostic(options.color, "error", location, &format!("{} [--werror]", message));
+        } else {
+            diagnostic(options.color, "warning", location, message);
+        }
+    };
+
+    let inputs: Vec<(&str, &str)> =
+        names.iter().zip(&sources).map(|(name, source)| (*name, source.as_str())).collect();
+    let program = compile_program(&mut parser, &inputs, options, &mut warn).unwrap_or_else(
+        |(name, error)| match name {
+            Some(name) => compile_error_in(options.color, name, error),
+            None => {
+                diagnostic(options.color, "error", None, &error.to_string());
+                process::exit(1);
+            }
+        },
+    );
+    if options.werror && warnings > 0 {
+        process::exit(1);
+    }
+    if options.emit == Some(Emit::AstRust) {
+        // The program as the code generator receives it, after -O when given
+        println!("vec![");
+        for node in &program.ast {
+            println!("    {},", node.to_rust());
+        }
+        println!("]");
+        return;
+    }
+    let codegen = &program.codegen;
+
+    if options.emit == Some(Emit::Cfg) {
+        // Graphviz dot: each block is labelled with its instruction count
+        println!("digraph cfg {{");
+        println!("    node [shape=box];");
+        for block in build_cfg(&codegen.output) {
+            println!(
+                "    \"{}\" [label=\"{}\\n{} instructions\"];",
+                block.name,
+                block.name,
+                block.instructions.len()
+            );
+            for successor in &block.successors {
+                println!("    \"{}\" -> \"{}\";", block.name, successor);
+            }
+        }
+        println!("}}");
+        return;
+    }
+    if options.emit == Some(Emit::Layout) {
+        for section in build_layout(&codegen.output) {
+            if section.name == ".text" {
+                println!("{:<8}{} instructions", section.name, section.instructions);
+            } else {
+                println!("{:<8}{} bytes", section.name, section.bytes);
+            }
+        }
+        return;
+    }
+
+    if options.emit == Some(Emit::MetricsJson) {
+        let instructions: usize =
+            build_layout(&codegen.output).iter().map(|section| section.instructions).sum();
+        let durations = [
+            ("lex", lex_time),
+            ("parse", program.parse_time),
+            ("optimize", program.optimize_time),
+            ("codegen", program.codegen_time),
+        ];
+        let nodes: usize = program.ast.iter().map(ASTNode::count_nodes).sum();
+        let variables = program.variables.len();
+        println!("{}", metrics_json(token_count, nodes, variables, instructions, &durations));
+        return;
+    }
+
+    if options.dry_run {
+        let existing = if Path::new(&options.output).exists() {
+            "overwriting existing file"
+        } else {
+            "new file"
+        };
+        println!(
+            "would write {} lines to {} ({})",
+            codegen.output.len(),
+            options.output,
+            existing
+        );
+        return;
+    }
+    // A file at the output path may be hand-written, so only replace it when asked to
+    if Path::new(&options.output).exists() && !options.force {
+        diagnostic(
+            options.color,
+            "error",
+            None,
+            &format!("{} already exists; pass --force to overwrite it", options.output),
+        );
+        process::exit(1);
+    }
+    if let Some(dir) = &options.out_dir {
+        fs::create_dir_all(dir).unwrap_or_else(|error| {
+            diagnostic(options.color, "error", None, &format!("can't create {}: {}", dir, error));
+            process::exit(1);
+        });
+    }
+    codegen.write_to(&options.output);
+
+    if options.listing {
+        let listing_path = Path::new(&options.output).with_extension("lst");
+        let listing = build_listing(&program.statements, &program.ranges, &codegen.output);
+        fs::write(&listing_path, listing.join("\n") + "\n").expect("Unable to write listing");
+        println!("Listing written to {}", listing_path.display());
+    }
+
+    if options.emit_makefile {
+        let makefile_path = Path::new(&options.output).with_extension("mk");
+        fs::write(&makefile_path, build_makefile(&options.output, options.use_libc, options.target_os)).expect("Unable to write makefile");
+        println!(
+            "Makefile written to {} (build with `make -f {}`)",
+            makefile_path.display(),
+            makefile_path.display()
+        );
+    }
+
+    if options.run {
+        match build_and_run(
+            &options.output,
+            options.keep_asm,
+            options.use_libc,
+            options.target_os,
+        ) {
+            Ok((stdout, code)) => {
+                print!("{}", stdout);
+                if code != 0 {
+                    eprintln!("Program exited with code {}", code);
+                    process::exit(code);
+                }
+            }
+            Err(message) => {
+                diagnostic(options.color, "error", None, &message);
+                process::exit(1);
+            }
+        }
+        return;
+    }
+
+    println!("Assembly code generated in {}", options.output);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compile_bytes_accepts_utf8() {
+        let assembly = compile_bytes("x = 1\nprint(x + 2)\n".as_bytes()).unwrap();
+        assert!(assembly.contains("_start:"));
+    }
+
+    #[test]
+    fn compile_bytes_rejects_invalid_utf8() {
+        let error = compile_bytes(b"x = 1\nprint(\xff)\n").unwrap_err();
+        assert_eq!(error, CompileError::InvalidUtf8 { line: 2, offset: 12 });
+        assert_eq!(error.code(), "E022");
+    }
+
+    #[test]
+    fn out_dir_names_output_after_input() {
+        assert_eq!(derive_output_path("foo/bar.vp", "out"), "out/bar.asm");
+    }
+
+    // The rules build the object and binary named after the assembly, wherever it was written
+    #[test]
+    fn makefile_names_real_outputs() {
+        let makefile = build_makefile("out/prog.asm", false, TargetOs::Linux);
+        let rules: Vec<&str> = makefile.lines().filter(|line| line.contains(':')).collect();
+        assert_eq!(
+            rules,
+            ["out/prog: out/prog.o", "out/prog.o: out/prog.asm", ".PHONY: clean", "clean:"]
+        );
+        assert!(makefile.contains("\tnasm -f elf64 out/prog.asm -o out/prog.o\n"), "{}", makefile);
+        assert!(makefile.contains("\tld out/prog.o -o out/prog\n"), "{}", makefile);
+        assert!(makefile.contains("\trm -f out/prog.o out/prog\n"), "{}", makefile);
+    }
+
+    #[test]
+    fn ast_as_rust() {
+        let node = ASTNode::BinaryOp {
+            left: Box::new(ASTNode::Number(1)),
+            operator: "+".to_string(),
+            right: Box::new(ASTNode::Variable("y".to_string(), Origin(None))),
+        };
+        assert_eq!(
+            node.to_rust(),
+            "ASTNode::BinaryOp { left: Box::new(ASTNode::Number(1)), \
+             operator: \"+\".to_string(), \
+             right: Box::new(ASTNode::Variable(\"y\".to_string(), Origin(None))) }"
+        );
+    }
+
+    // .bss holds a quadword per variable and the 20-byte print buffer
+    #[test]
+    fn bss_size_counts_variables_and_buffer() {
+        let assembly = compile("a = 1\nb = 2\nc = a + b\nprint(c)\n").unwrap();
+        let lines: Vec<String> = assembly.lines().map(str::to_string).collect();
+        let layout = build_layout(&lines);
+        let bss = layout.iter().find(|section| section.name == ".bss").unwrap();
+        assert_eq!(bss.bytes, 3 * 8 + 20);
+    }
+
+    // Directives are applied as the command line applies them, with no symbols defined
+    #[test]
+    fn compile_preprocesses() {
+        let assembly = |source: &str| compile(source).unwrap();
+        let conditional = "#if X\nprint(1)\n#else\nprint(2)\n#endif\n";
+        assert_eq!(assembly(conditional), assembly("print(2)\n"));
+        let unbalanced = compile("#if X\nprint(1)\n").unwrap_err();
+        assert!(matches!(unbalanced, CompileError::UnbalancedDirective(_)), "{:?}", unbalanced);
+    }
+
+    // compile() goes through compile_program like the command line, so it drops the statement
+    // after a `continue` too
+    #[test]
+    fn compile_removes_unreachable() {
+        let source = "x = 0\nwhile (x < 3) {\n    x = x + 1\n    continue\n    print(99)\n}\n";
+        let assembly = compile(source).unwrap();
+        assert!(!assembly.contains("mov rax, 99"), "{}", assembly);
+    }
+
+    #[test]
+    fn compile_errors_are_typed() {
+        let error = compile("x = 1abc\n").unwrap_err();
+        assert!(matches!(error, CompileError::InvalidNumber(_)), "{:?}", error);
+        assert_eq!(error.to_string(), "[E002] Invalid number literal '1abc' at 1:5");
+        assert!(matches!(compile("break\n"), Err(CompileError::MisplacedLoopControl(_))));
+        assert!(matches!(compile("print(nope(1))\n"), Err(CompileError::UnknownFunction(_))));
+    }
+
+    // An embedder's builtin goes through the same registry as isqrt, gcd and rand
+    #[test]
+    fn custom_builtin() {
+        fn emit_double(codegen: &mut CodeGenerator, args: &[ASTNode]) {
+            codegen.generate_node(&args[0]);
+            codegen.emit("    add rax, rax");
+        }
+        let mut builtins = default_builtins();
+        builtins.insert("double".to_string(), Builtin { arity: 1, emit: emit_double });
+
+        let source = "print(double(21) + isqrt(16))\n";
+        let ast = Parser::new(Lexer::new(source)).with_builtins(builtins.clone()).parse();
+        let mut codegen = CodeGenerator::new().with_builtins(builtins);
+        for node in &ast {
+            codegen.generate(node).unwrap();
+        }
+        assert!(codegen.output.iter().any(|line| line == "    add rax, rax"));
+
+        let error = catch_compile_error(|| Parser::new(Lexer::new(source)).parse()).unwrap_err();
+        assert!(matches!(error, CompileError::UnknownFunction(_)), "{:?}", error);
+    }
+
+    fn parse_with(source: &str, precedence: HashMap<String, (u8, Associativity)>) -> Vec<ASTNode> {
+        Parser::new(Lexer::new(source)).with_precedence(precedence).parse()
+    }
+
+    // The default table gives the usual grouping; swapping two levels regroups accordingly
+    #[test]
+    fn precedence_table() {
+        let source = "x = 1 + 2 * 3 - 4\ny = 8 / 4 / 2\n";
+        assert_eq!(
+            parse_with(source, default_precedence()),
+            parse("x = (1 + (2 * 3)) - 4\ny = (8 / 4) / 2\n")
+        );
+
+        let mut swapped = default_precedence();
+        swapped.insert("+".to_string(), (5, Associativity::Left));
+        swapped.insert("*".to_string(), (4, Associativity::Left));
+        swapped.insert("/".to_string(), (5, Associativity::Right));
+        assert_eq!(
+            parse_with(source, swapped),
+            parse("x = ((1 + 2) * 3) - 4\ny = 8 / (4 / 2)\n")
+        );
+    }
+
+    // Editing a line and re-lexing from it gives the tokens a full lex of the edited text does
+    #[test]
+    fn relex_matches_full_lex() {
+        let before = "x = 1\n/* note\n   more */ y = x + 2\nprint(y)\nz = 3\n";
+        let edits = [
+            ("y = x + 2", "y = x * 20 + 1"),
+            ("print(y)", "print(y, z)\nw = 4"),
+            ("   more */", "   more\n   and more */"),
+            ("z = 3\n", ""),
+        ];
+        for preserve_trivia in [false, true] {
+            for (old, new) in edits {
+                let after = before.replacen(old, new, 1);
+                let offset = before.find(old).unwrap();
+                let options = LexerOptions {
+                    preserve_trivia,
+                    ..LexerOptions::default()
+                };
+                let mut lexer = Lexer::new(before).with_options(options);
+                let mut tokens = lexer.tokenize_with_spans();
+                lexer.reset(&after);
+                lexer.relex_from(&mut tokens, offset);
+
+                let mut full = Lexer::new(&after).with_options(options);
+                assert_eq!(tokens, full.tokenize_with_spans(), "{:?} -> {:?}", old, new);
+            }
+        }
+    }
+
+    // for_each_child visits the same children map_children rebuilds
+    #[test]
+    fn children_visited_and_mapped_agree() {
+        let source = "macro twice(x) = x * 2\nif (a < 1) { print(a, \" \", twice(a)) } else { b, c = 1, 2 }\n\
+                      do { a = a + 1 } while (a < 3)\nmatch a { 1 { printf(\"%d\", a) } else { print(0) } }\n\
+                      for (i in range(2)) { static_assert(1) }\ndefer print(a / 2)\n";
+        for node in parse(source) {
+            let mut visited = Vec::new();
+            node.for_each_child(&mut |child| visited.push(child.clone()));
+            let mut mapped = Vec::new();
+            node.map_children(&mut |child| {
+                mapped.push(child.clone());
+                child
+            });
+            assert_eq!(visited, mapped);
+        }
+    }
+
+    // Folding gives what the generated idiv computes, and leaves the divisions it traps on
+    #[test]
+    fn signed_division_folds() {
+        assert_eq!(Optimizer::fold_binary("/", -7, 2), Some(-3));
+        assert_eq!(Optimizer::fold_binary("%", -7, 2), Some(-1));
+        assert_eq!(Optimizer::fold_binary("/", 7, -2), Some(-3));
+        assert_eq!(Optimizer::fold_binary("%", -8, 3), Some(-2));
+        assert_eq!(Optimizer::fold_binary("/", 1, 0), None);
+        assert_eq!(Optimizer::fold_binary("/", i64::MIN, -1), None);
+        assert_eq!(Optimizer::fold_binary("%", i64::MIN, -1), None);
+    }
+
+    // Each boolean identity with a constant operand, folded as in the -O passes
+    #[test]
+    fn boolean_identities_fold() {
+        let folded = |expression: &str| match &parse(&format!("r = ({})\n", expression))[..] {
+            [ASTNode::Assignment { value, .. }] => {
+                Optimizer::fold((**value).clone(), &HashMap::new())
+            }
+            other => panic!("not a single assignment: {:?}", other),
+        };
+        assert_eq!(folded("!!x"), folded("x != 0"));
+        assert_eq!(folded("!0").constant(), Some(1));
+        assert_eq!(folded("!1").constant(), Some(0));
+        assert_eq!(folded("x && 1"), folded("x != 0"));
+        assert_eq!(folded("x < 2 && 1"), folded("x < 2"));
+        assert_eq!(folded("x || 0"), folded("x != 0"));
+        assert_eq!(folded("x && 0").constant(), Some(0));
+        // The assignment still runs before the constant result
+        let kept = folded("(y = 1) && 0");
+        assert!(matches!(&kept, ASTNode::Sequence(items) if items.len() == 2), "{:?}", kept);
+        assert_eq!(kept, folded("(y = 1, 0)"));
+    }
+
+    // A backslash ending a line joins it to the next; anywhere else it is an error
+    #[test]
+    fn line_continuation() {
+        assert_eq!(parse("x = 1 + \\\n2\n"), parse("x = 1 + 2\n"));
+        let stray = compile("x = 1 \\ 2\n").unwrap_err();
+        assert!(matches!(stray, CompileError::UnexpectedCharacter(_)), "{:?}", stray);
+        assert_eq!(stray.code(), "E003");
+    }
+
+    #[test]
+    fn static_asserts() {
+        assert!(compile("size = 4\nstatic_assert(size * 2 == 8)\nprint(size)\n").is_ok());
+        let failed = compile("size = 4\nstatic_assert(size > 8)\n").unwrap_err();
+        assert!(matches!(failed, CompileError::StaticAssertFailed(_)), "{:?}", failed);
+        let unknown = compile("static_assert(rand(2) == 0)\n").unwrap_err();
+        assert!(matches!(unknown, CompileError::StaticAssertNotConstant(_)), "{:?}", unknown);
+        assert_eq!(failed.code(), unknown.code());
+    }
+
+    fn parse(source: &str) -> Vec<ASTNode> {
+        Parser::new(Lexer::new(source)).parse()
     }
-    codegen.emit_footer();
 
-    println!("Assembly code generated in output.asm");
+    // Inside a condition arithmetic binds tighter than comparison, comparison than `&&`, and
+    // `&&` than `||`
+    #[test]
+    fn precedence_in_conditions() {
+        let condition = |source: &str| match &parse(&format!("if {} {{ print(1) }}\n", source))[..] {
+            [ASTNode::If { condition, .. }] => (**condition).clone(),
+            other => panic!("not a single if: {:?}", other),
+        };
+        let cases = [
+            ("a + 1 < b * 2", "(a + 1) < (b * 2)"),
+            ("a < b && c == d", "(a < b) && (c == d)"),
+            ("a || b && c", "a || (b && c)"),
+            ("a == 1 || b - 1 >= c && d", "(a == 1) || (((b - 1) >= c) && d)"),
+        ];
+        for (implicit, explicit) in cases {
+            assert_eq!(condition(implicit), condition(explicit), "{}", implicit);
+        }
+        assert_ne!(condition("a + 1 < b * 2"), condition("a + (1 < b) * 2"));
+    }
+
+    #[test]
+    fn condition_parentheses_are_optional() {
+        assert_eq!(
+            parse("a = 1\nif a < 2 { print(a) } else { print(0) }\nwhile a < 3 { a = a + 1 }\n"),
+            parse("a = 1\nif (a < 2) { print(a) } else { print(0) }\nwhile (a < 3) { a = a + 1 }\n")
+        );
+    }
+
+    // Spans come from the parsed statements: macro arguments count once, parameters not at all
+    #[test]
+    fn symbol_index_locations() {
+        let source =
+            "a, b = 1, 2\nmacro twice(n) = n + n\nfor (i in range(b)) {\n  a = twice(a + i)\n}\n";
+        let index = build_symbol_index(&parse(source));
+        let locations = |spans: &[Span]| -> Vec<(usize, usize)> {
+            spans.iter().map(|span| (span.line, span.column)).collect()
+        };
+        let summary: Vec<_> = index
+            .symbols
+            .iter()
+            .map(|symbol| {
+                let assignments = locations(&symbol.assignments);
+                (symbol.name.as_str(), assignments, locations(&symbol.references))
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                ("a", vec![(1, 1), (4, 3)], vec![(4, 13)]),
+                ("b", vec![(1, 4)], vec![(3, 17)]),
+                ("i", vec![(3, 6)], vec![(4, 17)]),
+            ]
+        );
+    }
 }
@@ -0,0 +1,448 @@
+// Command-line options whose effect shows in the compiler's own output rather than in the
+// generated program.
+
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+
+// Write `source` to `NAME.vp` in the target's scratch directory and return its path
+fn source_file(name: &str, source: &str) -> PathBuf {
+    let input = Path::new(env!("CARGO_TARGET_TMPDIR")).join(format!("{}.vp", name));
+    fs::write(&input, source).unwrap();
+    input
+}
+
+fn viper(args: &[&str], input: &Path) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_Viper"))
+        .args(args)
+        .arg(input)
+        .env("RUST_BACKTRACE", "0")
+        .output()
+        .expect("failed to start the compiler")
+}
+
+#[test]
+fn dry_run_writes_nothing() {
+    let input = source_file("dry_run", "x = 1\nprint(x + 2)\n");
+    let assembly = input.with_extension("asm");
+    let _ = fs::remove_file(&assembly);
+    let path = assembly.to_str().unwrap();
+
+    let output = viper(&["--dry-run", "-o", path], &input);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(!assembly.exists(), "--dry-run wrote {}", path);
+
+    let written = viper(&["-o", path], &input);
+    assert!(written.status.success(), "{}", String::from_utf8_lossy(&written.stderr));
+    let lines = fs::read_to_string(&assembly).unwrap().lines().count();
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        format!("would write {} lines to {} (new file)\n", lines, path)
+    );
+}
+
+#[test]
+fn format_is_canonical_and_stable() {
+    let messy = "x=1   # set\nif(x<2){print( (x+1)*2 )}else{ y = x\nwhile x<5 {x=x+1}}\n\
+                 for(i in range(3)){print(i,width=4)}\nmask=0xFF+1_000\n";
+    let canonical = "\
+x = 1
+if (x < 2) {
+    print((x + 1) * 2)
+} else {
+    y = x
+    while (x < 5) {
+        x = x + 1
+    }
+}
+for (i in range(3)) {
+    print(i, width=4)
+}
+mask = 0xFF + 1_000
+";
+    let format = |name: &str, source: &str| {
+        let output = viper(&["--format"], &source_file(name, source));
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        String::from_utf8(output.stdout).unwrap()
+    };
+    assert_eq!(format("format_messy", messy), canonical);
+    assert_eq!(format("format_canonical", canonical), canonical);
+}
+
+// Generation stops with ProgramTooLarge once the code passes --max-instructions
+#[test]
+fn program_too_large() {
+    let source: String = (0..200).map(|i| format!("x{} = {}\n", i, i)).collect();
+    let input = source_file("too_large", &source);
+    let assembly = input.with_extension("asm");
+    let path = assembly.to_str().unwrap();
+
+    let output = viper(&["--force", "-o", path, "--max-instructions", "100"], &input);
+    assert!(!output.status.success());
+    let errors = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        errors.contains("[E011] Program too large: generated code exceeds 100 instructions"),
+        "{}",
+        errors
+    );
+
+    let output = viper(&["--force", "-o", path], &input);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+}
+
+// A loop whose condition is always true is reported unless something breaks out of it
+#[test]
+fn infinite_loop_warning() {
+    let warnings = |name: &str, source: &str| {
+        let input = source_file(name, source);
+        let assembly = input.with_extension("asm");
+        let output = viper(&["--force", "-o", assembly.to_str().unwrap()], &input);
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        String::from_utf8_lossy(&output.stderr).into_owned()
+    };
+    let endless = warnings("endless_loop", "while (1) {}\n");
+    assert!(endless.contains("warning: ") && endless.contains("never terminates"), "{}", endless);
+    assert_eq!(warnings("broken_loop", "while (1) { break }\n"), "");
+}
+
+#[test]
+fn invalid_utf8_source() {
+    let input = Path::new(env!("CARGO_TARGET_TMPDIR")).join("latin1.vp");
+    fs::write(&input, b"# caf\xe9\nprint(1)\n").unwrap();
+    let output = viper(&["--dry-run"], &input);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    let expected = "[E022] Source is not valid UTF-8 (invalid byte on line 1, byte offset 5)";
+    assert!(stderr.contains(expected), "{}", stderr);
+}
+
+// --color=always colours diagnostics even on a pipe, and --color=never never does
+#[test]
+fn color_modes() {
+    let input = source_file("color_modes", "x = 1\nwhile (1) { x = x + 1 }\n");
+    let stderr = |color: &str| {
+        let output = viper(&["--dry-run", color], &input);
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        String::from_utf8(output.stderr).unwrap()
+    };
+    let plain = stderr("--color=never");
+    assert!(plain.contains("warning"), "{}", plain);
+    assert!(!plain.contains('\x1b'), "{:?}", plain);
+    let colored = stderr("--color=always");
+    assert!(colored.contains("\x1b["), "{:?}", colored);
+    assert_eq!(stderr("--color=auto"), plain);
+}
+
+// An if/else is a diamond: the entry block branches to the two arms, which both reach the join
+#[test]
+fn if_else_control_flow() {
+    let input = source_file("if_else_cfg", "a = 1\nif (a < 2) { print(1) } else { print(2) }\n");
+    let output = viper(&["--emit=cfg"], &input);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let graph = String::from_utf8(output.stdout).unwrap();
+    // The program's own blocks come before the runtime routines, starting at int_to_string
+    let program = &graph[..graph.find("\"int_to_string\"").unwrap()];
+    let blocks = program.lines().filter(|line| line.contains("[label=")).count();
+    assert_eq!(blocks, 4, "{}", graph);
+    for edge in [
+        "\"_start\" -> \"else_1\"",
+        "\"_start\" -> \"bb1\"",
+        "\"bb1\" -> \"end_if_2\"",
+        "\"else_1\" -> \"end_if_2\"",
+    ] {
+        assert!(program.contains(edge), "{}:\n{}", edge, graph);
+    }
+    assert_eq!(program.matches(" -> ").count(), 4, "{}", graph);
+}
+
+// --explain prints the longer text for a code, in any case, and rejects codes it doesn't know
+#[test]
+fn explain_error_codes() {
+    let explain = |code: &str| {
+        Command::new(env!("CARGO_BIN_EXE_Viper"))
+            .args(["--explain", code])
+            .output()
+            .expect("failed to start the compiler")
+    };
+    let output = explain("E001");
+    assert!(output.status.success());
+    let text = String::from_utf8(output.stdout).unwrap();
+    assert!(text.starts_with("E001: A block comment was opened with `/*`"), "{}", text);
+    assert_eq!(explain("e001").stdout, text.as_bytes());
+
+    let output = explain("E999");
+    assert!(!output.status.success());
+    let errors = String::from_utf8_lossy(&output.stderr);
+    assert!(errors.contains("Unknown error code: E999"), "{}", errors);
+}
+
+// --strict-bool rejects a number as a condition but not a comparison; without it both compile
+#[test]
+fn strict_bool_conditions() {
+    let number = source_file("strict_number", "if (5) { print(1) }\n");
+    let comparison = source_file("strict_comparison", "a = 1\nb = 2\nif (a < b) { print(1) }\n");
+
+    let output = viper(&["--strict-bool", "--dry-run"], &number);
+    assert!(!output.status.success());
+    let errors = String::from_utf8_lossy(&output.stderr);
+    let expected = "[E012] Condition must be a Bool, found a Number at 1:4";
+    assert!(errors.contains(expected), "{}", errors);
+
+    for (args, input) in [
+        (&["--strict-bool", "--dry-run"][..], &comparison),
+        (&["--dry-run"][..], &number),
+    ] {
+        let output = viper(args, input);
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    }
+}
+
+// An unused variable is only a warning, which --werror turns into a failure with no output
+#[test]
+fn werror_unused_variable() {
+    let input = source_file("unused_variable", "x = 1\ny = 2\nprint(y)\n_z = 3\n");
+    let assembly = input.with_extension("asm");
+    let _ = fs::remove_file(&assembly);
+    let path = assembly.to_str().unwrap();
+
+    let output = viper(&["--werror", "--force", "-o", path], &input);
+    assert!(!output.status.success());
+    let errors = String::from_utf8_lossy(&output.stderr);
+    assert!(errors.contains("variable 'x' is assigned but never read [--werror]"), "{}", errors);
+    assert_eq!(errors.lines().count(), 1, "{}", errors);
+    assert!(!assembly.exists());
+
+    let output = viper(&["--force", "-o", path], &input);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let warnings = String::from_utf8_lossy(&output.stderr);
+    assert!(warnings.contains("warning: "), "{}", warnings);
+    assert!(assembly.exists());
+}
+
+// A compile error names its input, with --stdin-name standing for `-`
+#[test]
+fn stdin_name_in_compile_errors() {
+    let mut compiler = Command::new(env!("CARGO_BIN_EXE_Viper"))
+        .args(["--dry-run", "--stdin-name", "piped.vp", "-"])
+        .stdin(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("failed to start the compiler");
+    compiler.stdin.take().unwrap().write_all(b"x = 1abc\n").unwrap();
+    let output = compiler.wait_with_output().unwrap();
+    assert!(!output.status.success());
+    let errors = String::from_utf8_lossy(&output.stderr);
+    let expected = "error: piped.vp: [E002] Invalid number literal '1abc' at 1:5";
+    assert!(errors.contains(expected), "{}", errors);
+
+    let input = source_file("named_error", "x = 1abc\n");
+    let errors = String::from_utf8(viper(&["--dry-run"], &input).stderr).unwrap();
+    assert!(errors.contains(&format!("error: {}: [E002]", input.display())), "{}", errors);
+}
+
+// A statement after `continue` is reported where it is written and generates no code
+#[test]
+fn unreachable_statement_removed() {
+    let source = "x = 0\nwhile (x < 3) {\n    x = x + 1\n    continue\n    print(99)\n}\nprint(x)\n";
+    let input = source_file("unreachable", source);
+    let assembly = input.with_extension("asm");
+    let output = viper(&["--force", "-o", assembly.to_str().unwrap()], &input);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let warnings = String::from_utf8_lossy(&output.stderr);
+    let expected = format!(
+        "warning: {}:5:5: 1 statement(s) after 'break'/'continue' can never run and were removed",
+        input.display()
+    );
+    assert!(warnings.contains(&expected), "{}", warnings);
+    let code = fs::read_to_string(&assembly).unwrap();
+    assert!(!code.contains("mov rax, 99"), "{}", code);
+}
+
+// --listing writes NAME.lst with each source line as a comment before the code generated for it
+#[test]
+fn listing_interleaves_source() {
+    let input = source_file("listing", "a = 1\n");
+    let assembly = input.with_extension("asm");
+    let output = viper(&["--listing", "--force", "-o", assembly.to_str().unwrap()], &input);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let listing = fs::read_to_string(input.with_extension("lst")).unwrap();
+    let lines: Vec<&str> = listing.lines().map(str::trim).collect();
+    let source = lines.iter().position(|line| *line == ";    1: a = 1").unwrap();
+    assert_eq!(lines[source + 1], "mov rax, 1", "{}", listing);
+}
+
+// --out-dir names the output itself, so an explicit -o as well is a usage error
+#[test]
+fn out_dir_rejects_explicit_output() {
+    let input = source_file("out_dir", "print(1)\n");
+    let directory = env!("CARGO_TARGET_TMPDIR");
+    let output = viper(&["--out-dir", directory, "-o", "other.asm"], &input);
+    assert!(!output.status.success());
+    let errors = String::from_utf8_lossy(&output.stderr);
+    assert!(errors.contains("-o can't be combined with --out-dir"), "{}", errors);
+}
+
+// Several inputs make one program: a variable assigned in one file can be printed by the next,
+// and one assigned in two files is reported
+#[test]
+fn inputs_share_variables() {
+    let defines = source_file("defines_x", "x = 5\n");
+    let prints = source_file("prints_x", "print(x)\n");
+    let assembly = prints.with_extension("asm");
+    let output = viper(
+        &["--force", "-o", assembly.to_str().unwrap(), defines.to_str().unwrap()],
+        &prints,
+    );
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(String::from_utf8_lossy(&output.stderr), "");
+    let code = fs::read_to_string(&assembly).unwrap();
+    let lines: Vec<&str> = code.lines().map(str::trim).collect();
+    let store = lines.iter().position(|line| *line == "mov [x], rax").unwrap();
+    assert_eq!(lines[store + 1], "mov rax, [x]", "{}", code);
+
+    let redefines = source_file("redefines_x", "x = 6\nprint(x)\n");
+    let output = viper(&["--dry-run", defines.to_str().unwrap()], &redefines);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let warnings = String::from_utf8_lossy(&output.stderr);
+    let expected = format!(
+        "warning: variable 'x' is assigned in both {} and {}",
+        defines.display(),
+        redefines.display()
+    );
+    assert!(warnings.contains(&expected), "{}", warnings);
+}
+
+// static_assert sees the constants of earlier inputs, and a failed one names its input
+#[test]
+fn static_assert_across_inputs() {
+    let defines = source_file("defines_size", "size = 4\n");
+    let checks = source_file("checks_size", "static_assert(size == 4)\nprint(size)\n");
+    let output = viper(&["--dry-run", defines.to_str().unwrap()], &checks);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+    let fails = source_file("fails_size", "print(size)\nstatic_assert(size > 8)\n");
+    let output = viper(&["--dry-run", defines.to_str().unwrap()], &fails);
+    assert!(!output.status.success());
+    let expected = format!(
+        "error: {}: [E018] Static assertion failed: size > 8 at 2:1",
+        fails.display()
+    );
+    assert!(String::from_utf8_lossy(&output.stderr).contains(&expected));
+}
+
+// An existing output file is only replaced with --force
+#[test]
+fn existing_output_needs_force() {
+    let input = source_file("overwrite", "print(1)\n");
+    let assembly = input.with_extension("asm");
+    let path = assembly.to_str().unwrap();
+    fs::write(&assembly, "keep\n").unwrap();
+
+    let output = viper(&["-o", path], &input);
+    assert!(!output.status.success());
+    let errors = String::from_utf8_lossy(&output.stderr);
+    let expected = format!("error: {} already exists; pass --force to overwrite it", path);
+    assert!(errors.contains(&expected), "{}", errors);
+    assert_eq!(fs::read_to_string(&assembly).unwrap(), "keep\n");
+
+    let output = viper(&["--force", "-o", path], &input);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_ne!(fs::read_to_string(&assembly).unwrap(), "keep\n");
+}
+
+// The number after `"key": ` in a flat JSON object
+fn json_field(json: &str, key: &str) -> Option<u64> {
+    let start = json.find(&format!("\"{}\": ", key))? + key.len() + 4;
+    let digits: String = json[start..].chars().take_while(char::is_ascii_digit).collect();
+    digits.parse().ok()
+}
+
+// --emit=metrics-json reports counts that are fixed for a given program, and a duration for each
+// phase
+#[test]
+fn metrics_of_known_program() {
+    let source = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/programs/arithmetic.vp");
+    let output = viper(&["--emit=metrics-json"], &source);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let json = String::from_utf8(output.stdout).unwrap();
+
+    assert_eq!(json_field(&json, "tokens"), Some(59), "{}", json);
+    assert_eq!(json_field(&json, "nodes"), Some(40), "{}", json);
+    assert_eq!(json_field(&json, "variables"), Some(3), "{}", json);
+    assert!(json_field(&json, "instructions").is_some_and(|count| count > 0), "{}", json);
+    for phase in ["lex", "parse", "optimize", "codegen"] {
+        assert!(json_field(&json, phase).is_some(), "{}: {}", phase, json);
+    }
+}
+
+// `#if NAME` / `#else` / `#endif` keep or drop source lines depending on the symbols given with
+// --define. The program is printed back with --format, so only the compiler runs.
+#[test]
+fn conditional_compilation() {
+    let input = source_file(
+        "preprocessor",
+        "print(1)\n#if DEBUG\nprint(2)\n    #if VERBOSE\n    print(3)\n    #endif\n#else\nprint(4)\n\
+         #endif\nprint(5)\n",
+    );
+    let format = |defines: &[&str]| {
+        let args: Vec<&str> = defines.iter().flat_map(|name| ["--define", name]).collect();
+        let output = viper(&[&args[..], &["--format"]].concat(), &input);
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        String::from_utf8(output.stdout).unwrap()
+    };
+    assert_eq!(format(&[]), "print(1)\nprint(4)\nprint(5)\n");
+    assert_eq!(format(&["DEBUG"]), "print(1)\nprint(2)\nprint(5)\n");
+    assert_eq!(format(&["DEBUG", "VERBOSE"]), "print(1)\nprint(2)\nprint(3)\nprint(5)\n");
+}
+
+// --emit=symbols-json lists where each variable is assigned and read
+#[test]
+fn symbols_of_small_program() {
+    let input = source_file(
+        "symbols",
+        "x = 1\nprint(x + y)\nfor (i in range(3)) { x = x + i }\nmacro sq(n) = n * n\n\
+         print(sq(x))\n",
+    );
+    let output = viper(&["--emit=symbols-json"], &input);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let json = String::from_utf8(output.stdout).unwrap();
+
+    let at = |line: u32, column: u32| format!("{{\"line\": {}, \"column\": {}}}", line, column);
+    let expected = format!(
+        "[{}, {}, {}]\n",
+        format_args!(
+            "{{\"name\": \"x\", \"assignments\": [{}, {}], \"references\": [{}, {}, {}]}}",
+            at(1, 1),
+            at(3, 23),
+            at(2, 7),
+            at(3, 27),
+            at(5, 10)
+        ),
+        format_args!("{{\"name\": \"y\", \"assignments\": [], \"references\": [{}]}}", at(2, 11)),
+        format_args!(
+            "{{\"name\": \"i\", \"assignments\": [{}], \"references\": [{}]}}",
+            at(3, 6),
+            at(3, 31)
+        ),
+    );
+    // The macro's parameter `n` is not a variable
+    assert_eq!(json, expected);
+}
+
+// The level follows the last `=`, so operators containing one can be overridden, and anything
+// that isn't a binary operator is rejected
+#[test]
+fn precedence_overrides() {
+    let input = source_file("precedence", "print(a <= b + c)\n");
+    let output = viper(&["--precedence", "<==6:left", "--format"], &input);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "print((a <= b) + c)\n");
+
+    for unknown in ["foo=3:left", "foo=3", "!=4"] {
+        let output = viper(&["--precedence", unknown, "--format"], &input);
+        assert!(!output.status.success(), "--precedence {} was accepted", unknown);
+        let errors = String::from_utf8_lossy(&output.stderr);
+        assert!(errors.contains("--precedence expects OP=LEVEL"), "{}", errors);
+    }
+}
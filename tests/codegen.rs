@@ -0,0 +1,275 @@
+// Checks on the assembly generated for small programs, for code shapes the output of a run
+// can't show.
+
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+// Compile `source` with the extra arguments and return its instructions, trimmed
+fn compile(name: &str, source: &str, args: &[&str]) -> Vec<String> {
+    let directory = Path::new(env!("CARGO_TARGET_TMPDIR"));
+    let input = directory.join(format!("{}.vp", name));
+    let assembly = directory.join(format!("{}.asm", name));
+    fs::write(&input, source).unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_Viper"))
+        .args(args)
+        .arg("--force")
+        .arg("-o")
+        .arg(&assembly)
+        .arg(&input)
+        .output()
+        .expect("failed to start the compiler");
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    fs::read_to_string(&assembly).unwrap().lines().map(|line| line.trim().to_string()).collect()
+}
+
+// Under -O a loop over a small constant range becomes copies of its body, with no loop left
+#[test]
+fn unrolled_loop() {
+    let source = "for (i in range(3)) { print(1) }\n";
+    let prints = |instructions: &[String]| {
+        instructions.iter().filter(|line| *line == "call int_to_string").count()
+    };
+    let branches = |instructions: &[String]| {
+        instructions.iter().filter(|line| line.starts_with("for_loop")).count()
+    };
+
+    let unrolled = compile("unrolled_loop", source, &["-O"]);
+    assert_eq!(prints(&unrolled), 3, "{}", unrolled.join("\n"));
+    assert_eq!(branches(&unrolled), 0, "{}", unrolled.join("\n"));
+
+    let kept = compile("kept_loop", source, &["-O", "--max-unroll", "2"]);
+    assert_eq!(prints(&kept), 1, "{}", kept.join("\n"));
+    assert_eq!(branches(&kept), 1, "{}", kept.join("\n"));
+}
+
+// Helpers shared by several features are emitted once, however many of them use it
+#[test]
+fn shared_subroutines_emitted_once() {
+    let instructions = compile(
+        "shared_subroutines",
+        "x = 7\nprint(x)\neprint(x, width=3)\nprintf(\"%d\\n\", 10 / x)\n",
+        &["--safe-div", "--runtime-base-toggle"],
+    );
+    for label in ["int_to_string:", "division_by_zero:", "read_print_base:"] {
+        let count = instructions.iter().filter(|line| *line == label).count();
+        assert_eq!(count, 1, "{}\n{}", label, instructions.join("\n"));
+    }
+}
+
+// A variable named like a register gets a label nasm can't mistake for the register
+#[test]
+fn register_named_variables() {
+    let source = "rax = 1\nrcx = rax + 1\nprint(rcx)\n";
+    let instructions = compile("register_named_variables", source, &[]);
+    let assembly = instructions.join("\n");
+    assert!(!instructions.iter().any(|line| line.starts_with("rax ")), "{}", assembly);
+    for store in ["mov [rax], rax", "mov [rcx], rax"] {
+        assert!(!instructions.iter().any(|line| line == store), "{}", assembly);
+    }
+    assert!(instructions.iter().any(|line| line == "v_726178 resq 1"), "{}", assembly);
+}
+
+// Under -O a subexpression used twice in a statement is computed once into a temporary
+#[test]
+fn repeated_subexpression_computed_once() {
+    // rand() keeps `a` and `b` unknown, so nothing folds; each rand() call has an add of its own
+    let source = "a = rand(10)\nb = rand(10)\nprint((a + b) * (a + b))\n";
+    let sums = |instructions: &[String]| {
+        instructions.iter().filter(|line| *line == "add rax, rbx").count()
+    };
+
+    let plain = compile("repeated_plain", source, &[]);
+    assert_eq!(sums(&plain), 4, "{}", plain.join("\n"));
+    let optimized = compile("repeated_optimized", source, &["-O"]);
+    assert_eq!(sums(&optimized), 3, "{}", optimized.join("\n"));
+    let stored = optimized.iter().any(|line| line.starts_with("mov [__cse_"));
+    assert!(stored, "{}", optimized.join("\n"));
+}
+
+// Under -O a variable assigned a constant is replaced by it in later statements, unless a
+// conditional assignment may have changed it in between
+#[test]
+fn constant_propagation() {
+    let propagated = compile("propagated", "a = 5\nb = a + 1\nprint(b)\n", &["-O"]);
+    assert!(propagated.iter().any(|line| line == "mov rax, 6"), "{}", propagated.join("\n"));
+    assert!(!propagated.iter().any(|line| line == "mov rax, [a]"), "{}", propagated.join("\n"));
+
+    let source = "a = 5\nif (rand(2)) { a = rand(9) }\nb = a + 1\nprint(b)\n";
+    let blocked = compile("propagation_blocked", source, &["-O"]);
+    assert!(!blocked.iter().any(|line| line == "mov rax, 6"), "{}", blocked.join("\n"));
+    assert!(blocked.iter().any(|line| line == "mov rax, [a]"), "{}", blocked.join("\n"));
+}
+
+// macOS takes BSD syscall numbers (class 2 << 24 plus the number) and enters at `_main`
+#[test]
+fn macos_syscall_numbers() {
+    let exits = |name: &str, args: &[&str]| {
+        let instructions = compile(name, "print(1)\n", args);
+        let exit = instructions.iter().find(|line| line.ends_with("; syscall: exit"));
+        (exit.cloned().unwrap(), instructions)
+    };
+    let (exit, instructions) = exits("macos_exit", &["--target-os=macos"]);
+    assert!(exit.starts_with(&format!("mov rax, {} ", 0x2000001)), "{}", exit);
+    assert!(instructions.iter().any(|line| line == "global _main"));
+
+    let (exit, instructions) = exits("linux_exit", &[]);
+    assert!(exit.starts_with("mov rax, 60 "), "{}", exit);
+    assert!(instructions.iter().any(|line| line == "global _start"));
+}
+
+// A `local` lives in the stack frame addressed from r15, a `global` gets a slot in .bss
+#[test]
+fn storage_hints() {
+    let instructions = compile("storage_hints", "local x = 1\nglobal y = 2\nprint(x + y)\n", &[]);
+    let assembly = instructions.join("\n");
+    assert!(instructions.iter().any(|line| line == "y resq 1"), "{}", assembly);
+    assert!(!instructions.iter().any(|line| line == "x resq 1"), "{}", assembly);
+    assert!(instructions.iter().any(|line| line == "mov [r15 - 8], rax"), "{}", assembly);
+    assert!(instructions.iter().any(|line| line == "mov [y], rax"), "{}", assembly);
+    assert!(!assembly.contains("[x]"), "{}", assembly);
+}
+
+// `"foo" + "bar"` folds to one literal at compile time, sharing the .data entry of a plain "foobar"
+#[test]
+fn concatenated_literals_fold() {
+    let instructions = compile(
+        "concatenated_literals",
+        "printf(\"foo\" + \"bar\")\nprintf(\"foobar\")\nprintf(\"fo\" + \"o\" + \"bar\")\n",
+        &[],
+    );
+    let foobar: Vec<String> = "foobar".bytes().map(|byte| byte.to_string()).collect();
+    let entry = format!("db {}, 0", foobar.join(", "));
+    let data: Vec<&String> = instructions.iter().filter(|line| line.contains(" db ")).collect();
+    let entries = data.iter().filter(|line| line.ends_with(&entry)).count();
+    assert_eq!(entries, 1, "{:?}", data);
+    assert_eq!(data.len(), 2, "{:?}", data);
+}
+
+// `a / b` and `a % b` together are one signed division, its quotient and remainder both used
+#[test]
+fn division_and_remainder_share_one_idiv() {
+    let source = "a = rand(100) - 50\nb = 3\nprint(a / b + a % b)\n";
+    let instructions = compile("divmod", source, &["-O"]);
+    let assembly = instructions.join("\n");
+    let divisions = instructions.iter().filter(|line| line.starts_with("idiv")).count();
+    assert_eq!(divisions, 1, "{}", assembly);
+}
+
+// `continue` in a for loop jumps to the step that advances the counter, not back to the test
+#[test]
+fn for_continue_runs_the_step() {
+    let instructions = compile(
+        "for_continue_step",
+        "for (i in range(10)) {\n    if (i % 2 == 0) {\n        continue\n    }\n    print(i)\n}\n",
+        &[],
+    );
+    let assembly = instructions.join("\n");
+    let step = instructions.iter().position(|line| line.starts_with("for_step_")).unwrap();
+    let label = instructions[step].trim_end_matches(':');
+    assert!(instructions[step..].iter().any(|line| line.starts_with("inc ")), "{}", assembly);
+    let continues = instructions[..step].iter().filter(|line| **line == format!("jmp {}", label));
+    assert_eq!(continues.count(), 1, "{}", assembly);
+    let back_edges = instructions.iter().filter(|line| line.starts_with("jmp for_loop_"));
+    assert_eq!(back_edges.count(), 1, "{}", assembly);
+}
+
+// Under -O a jump to a label that only jumps on goes straight to the final target, and the label
+// it skipped is removed
+#[test]
+fn jump_chains_are_threaded() {
+    let source = "a = rand(2)\nwhile (a < 5) {\n    a = a + 1\n    if (a == 3) { print(a) }\n}\n";
+    let plain = compile("jump_chain_plain", source, &[]);
+    let skipped = plain.iter().position(|line| line.starts_with("je end_if_")).unwrap();
+    let label = format!("{}:", &plain[skipped]["je ".len()..]);
+    let at = plain.iter().position(|line| *line == label).unwrap();
+    assert!(plain[at + 1].starts_with("jmp loop_"), "{}", plain.join("\n"));
+    let final_target = &plain[at + 1]["jmp ".len()..];
+
+    let threaded = compile("jump_chain_threaded", source, &["-O"]);
+    let assembly = threaded.join("\n");
+    assert!(threaded.contains(&format!("je {}", final_target)), "{}", assembly);
+    assert!(!assembly.contains("end_if_"), "{}", assembly);
+}
+
+// The stack is aligned to 16 bytes at entry, and again right before each call into libc
+#[test]
+fn stack_aligned_at_entry_and_calls() {
+    let entry = |instructions: &[String], label: &str| {
+        let start = instructions.iter().position(|line| line == label).unwrap();
+        instructions[start + 1].clone()
+    };
+    let raw = compile("aligned_entry", "print(1)\n", &[]);
+    assert_eq!(entry(&raw, "_start:"), "and rsp, -16", "{}", raw.join("\n"));
+
+    let libc = compile("aligned_calls", "print(1)\n", &["--use-libc"]);
+    assert_eq!(entry(&libc, "main:"), "and rsp, -16", "{}", libc.join("\n"));
+    let calls: Vec<usize> =
+        (0..libc.len()).filter(|&i| libc[i].starts_with("call printf")).collect();
+    assert!(!calls.is_empty(), "{}", libc.join("\n"));
+    for call in calls {
+        assert_eq!(libc[call - 1], "and rsp, -16", "{}", libc.join("\n"));
+    }
+}
+
+// Under -O the then-branch falls through from the conditional jump and the else-branch is moved
+// out of line, after the exit syscall
+#[test]
+fn cold_else_placed_after_exit() {
+    let source = "a = rand(2)\nif (a) {\n    print(7)\n} else {\n    print(8)\n}\n";
+    let instructions = compile("cold_else", source, &["-O"]);
+    let assembly = instructions.join("\n");
+    let position = |wanted: &dyn Fn(&str) -> bool| {
+        instructions.iter().position(|line| wanted(line)).unwrap()
+    };
+    let branch = position(&|line| line.starts_with("je else_"));
+    assert_eq!(instructions[branch + 1], "mov rax, 7", "{}", assembly);
+    let exit = position(&|line| line.ends_with("; syscall: exit"));
+    assert!(!instructions[branch..exit].iter().any(|line| line.starts_with("jmp")), "{}", assembly);
+    let label = format!("{}:", &instructions[branch]["je ".len()..]);
+    let else_branch = position(&|line| line == label);
+    assert!(exit < else_branch, "{}", assembly);
+    assert_eq!(instructions[else_branch + 1], "mov rax, 8", "{}", assembly);
+}
+
+// A macro call is replaced by the macro's body, which -O then folds like any other expression
+#[test]
+fn macro_call_folds() {
+    let source = "macro square(x) = x * x\nprint(square(3))\n";
+    let expanded = compile("macro_expanded", source, &[]);
+    assert!(expanded.iter().any(|line| line == "imul rax, rbx"), "{}", expanded.join("\n"));
+    let folded = compile("macro_folded", source, &["-O"]);
+    assert!(folded.iter().any(|line| line == "mov rax, 9"), "{}", folded.join("\n"));
+    assert!(!folded.iter().any(|line| line.starts_with("imul")), "{}", folded.join("\n"));
+}
+
+// Under --safe-div each divisor check passes the line of its own division to the error report
+#[test]
+fn safe_div_reports_the_division_line() {
+    let source = "a = 10\nb = rand(3)\nprint(a / b)\nc = 1\nprint(c % b)\n";
+    let instructions = compile("safe_div_lines", source, &["--safe-div"]);
+    let lines: Vec<&str> = (1..instructions.len())
+        .filter(|&i| instructions[i] == "jmp division_by_zero")
+        .map(|i| instructions[i - 1].as_str())
+        .collect();
+    assert_eq!(lines, ["mov rax, 3", "mov rax, 5"], "{}", instructions.join("\n"));
+}
+
+// With --annotate-folds a constant -O computed carries the expression it came from
+#[test]
+fn annotated_folds() {
+    let args = ["-O", "--annotate-folds"];
+    let instructions = compile("annotated_folds", "print(2 + 3 * 4)\n", &args);
+    let annotated = instructions.iter().any(|line| line == "mov rax, 14 ; folded from 2 + 3 * 4");
+    assert!(annotated, "{}", instructions.join("\n"));
+}
+
+// Literals keep their exact 64-bit value, including those an f64 would round
+#[test]
+fn large_literals_are_exact() {
+    let source = "print(9223372036854775806)\nprint(9007199254740993)\n";
+    let instructions = compile("large_literals", source, &[]);
+    for load in ["mov rax, 9223372036854775806", "mov rax, 9007199254740993"] {
+        assert!(instructions.iter().any(|line| line == load), "{}", instructions.join("\n"));
+    }
+}
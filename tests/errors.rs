@@ -0,0 +1,59 @@
+// Compile-error tests: every `tests/errors/NAME.vp` must fail to compile, reporting the error in
+// `tests/errors/NAME.expected` (without the `error: ` and the input's name before it). Only the
+// compiler runs, so unlike the golden-file tests these need no nasm.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+#[test]
+fn error_programs() {
+    let programs = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/errors");
+    let mut sources: Vec<PathBuf> = fs::read_dir(&programs)
+        .expect("tests/errors is missing")
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|extension| extension == "vp"))
+        .collect();
+    sources.sort();
+    assert!(!sources.is_empty(), "no programs in {}", programs.display());
+
+    let mut failures = Vec::new();
+    for source in &sources {
+        let name = source.file_stem().unwrap().to_string_lossy();
+        let expected = match fs::read_to_string(source.with_extension("expected")) {
+            Ok(expected) => expected.trim_end().to_string(),
+            Err(_) => {
+                failures.push(format!("{}: no {}.expected", name, name));
+                continue;
+            }
+        };
+        let assembly = Path::new(env!("CARGO_TARGET_TMPDIR")).join(format!("{}.asm", name));
+        let output = Command::new(env!("CARGO_BIN_EXE_Viper"))
+            .arg("--force")
+            .arg("-o")
+            .arg(&assembly)
+            .arg(source)
+            .env("RUST_BACKTRACE", "0")
+            .output()
+            .expect("failed to start the compiler");
+
+        let errors = String::from_utf8_lossy(&output.stderr);
+        let located = format!("{}: ", source.display());
+        let reported = |line: &str| {
+            let message = line.strip_prefix("error: ")?.strip_prefix(&located)?;
+            Some(message == expected)
+        };
+        if output.status.success() {
+            failures.push(format!("{}: compiled, expected: {}", name, expected));
+        } else if !errors.lines().any(|line| reported(line) == Some(true)) {
+            failures.push(format!("{}: expected: {}\n{}", name, expected, errors));
+        }
+    }
+    assert!(
+        failures.is_empty(),
+        "{} of {} programs failed:\n{}",
+        failures.len(),
+        sources.len(),
+        failures.join("\n")
+    );
+}
@@ -0,0 +1,100 @@
+// End-to-end tests: every `tests/programs/NAME.vp` is compiled and run with `--run`, and its
+// output must match `tests/programs/NAME.expected`. To add a test, drop in a new pair of files.
+// A `tests/programs/NAME.stderr` next to them, if any, must match what the program writes to
+// stderr, a `tests/programs/NAME.args` holds extra compiler options, separated by whitespace, and
+// a `tests/programs/NAME.status` the exit status the program must end with instead of 0.
+// Running the programs needs nasm and a linker, so without nasm the test is skipped, unless the
+// `CI` environment variable is set: then it fails.
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+// The lines that differ, as `-expected` / `+actual` pairs with their line numbers
+fn diff(expected: &str, actual: &str) -> String {
+    let expected: Vec<&str> = expected.lines().collect();
+    let actual: Vec<&str> = actual.lines().collect();
+    let mut report = String::new();
+    for line in 0..expected.len().max(actual.len()) {
+        let (want, got) = (expected.get(line), actual.get(line));
+        if want != got {
+            if let Some(want) = want {
+                report.push_str(&format!("  {:>3} -{}\n", line + 1, want));
+            }
+            if let Some(got) = got {
+                report.push_str(&format!("  {:>3} +{}\n", line + 1, got));
+            }
+        }
+    }
+    report
+}
+
+#[test]
+fn golden_programs() {
+    if Command::new("nasm").arg("-v").output().is_err() {
+        // A CI run that skipped them would pass without testing anything
+        assert!(env::var_os("CI").is_none(), "nasm not found, and CI is set: install nasm");
+        eprintln!("nasm not found; skipping the golden-file tests");
+        return;
+    }
+
+    let programs = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/programs");
+    let mut sources: Vec<PathBuf> = fs::read_dir(&programs)
+        .expect("tests/programs is missing")
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|extension| extension == "vp"))
+        .collect();
+    sources.sort();
+    assert!(!sources.is_empty(), "no programs in {}", programs.display());
+
+    let mut failures = Vec::new();
+    for source in &sources {
+        let name = source.file_stem().unwrap().to_string_lossy();
+        let expected = match fs::read_to_string(source.with_extension("expected")) {
+            Ok(expected) => expected,
+            Err(_) => {
+                failures.push(format!("{}: no {}.expected", name, name));
+                continue;
+            }
+        };
+        // Build in the target directory so parallel runs don't share output.asm
+        let assembly = Path::new(env!("CARGO_TARGET_TMPDIR")).join(format!("{}.asm", name));
+        let args = fs::read_to_string(source.with_extension("args")).unwrap_or_default();
+        let output = Command::new(env!("CARGO_BIN_EXE_Viper"))
+            .args(args.split_whitespace())
+            .arg("--run")
+            .arg("--force")
+            .arg("-o")
+            .arg(&assembly)
+            .arg(source)
+            .output()
+            .expect("failed to start the compiler");
+
+        let actual = String::from_utf8_lossy(&output.stdout);
+        // The program inherits the compiler's stderr
+        let errors = String::from_utf8_lossy(&output.stderr);
+        let expected_errors = fs::read_to_string(source.with_extension("stderr")).ok();
+        let status = fs::read_to_string(source.with_extension("status"))
+            .map_or(0, |status| status.trim().parse().expect("a status is a number"));
+        if output.status.code() != Some(status) {
+            failures.push(format!(
+                "{}: exited with {}\n{}",
+                name,
+                output.status,
+                String::from_utf8_lossy(&output.stderr)
+            ));
+        } else if actual != expected {
+            failures.push(format!("{}: output differs\n{}", name, diff(&expected, &actual)));
+        } else if let Some(expected_errors) = expected_errors.filter(|expected| *expected != errors) {
+            failures.push(format!("{}: stderr differs\n{}", name, diff(&expected_errors, &errors)));
+        }
+    }
+    assert!(
+        failures.is_empty(),
+        "{} of {} programs failed:\n{}",
+        failures.len(),
+        sources.len(),
+        failures.join("\n")
+    );
+}
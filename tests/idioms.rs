@@ -0,0 +1,33 @@
+// Zeroing a register and comparing it with zero use the shorter `xor` and `test` idioms.
+
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+#[test]
+fn zero_idioms() {
+    let directory = Path::new(env!("CARGO_TARGET_TMPDIR"));
+    let source = directory.join("zero_idioms.vp");
+    let assembly = directory.join("zero_idioms.asm");
+    fs::write(
+        &source,
+        "x = 0\nif x { print(1) }\nwhile x { x = 0 }\nmatch x { 0 { print(2) } }\nfor (i in range(3)) { print(i) }\n",
+    )
+    .unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_Viper"))
+        .arg("--force")
+        .arg("-o")
+        .arg(&assembly)
+        .arg(&source)
+        .output()
+        .expect("failed to start the compiler");
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+    let assembly = fs::read_to_string(&assembly).unwrap();
+    let instructions: Vec<&str> = assembly.lines().map(str::trim).collect();
+    for slow in ["mov rax, 0", "cmp rax, 0"] {
+        assert!(!instructions.iter().any(|line| line.starts_with(slow)), "{}:\n{}", slow, assembly);
+    }
+    assert!(instructions.contains(&"xor eax, eax"), "{}", assembly);
+    assert!(instructions.contains(&"test rax, rax"), "{}", assembly);
+}
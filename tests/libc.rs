@@ -0,0 +1,25 @@
+// A program built with --use-libc prints through printf and is linked with the C compiler.
+// Running it needs nasm and cc, so the test is ignored by default: `cargo test -- --ignored`
+// runs it.
+
+use std::path::Path;
+use std::process::Command;
+
+#[test]
+#[ignore = "needs nasm and cc"]
+fn prints_through_printf() {
+    let directory = Path::new(env!("CARGO_TARGET_TMPDIR"));
+    let source = directory.join("use_libc.vp");
+    std::fs::write(&source, "x = 6\nprint(x * 7)\nprint(-x, width=4)\n").unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_Viper"))
+        .arg("--use-libc")
+        .arg("--run")
+        .arg("--force")
+        .arg("-o")
+        .arg(directory.join("use_libc.asm"))
+        .arg(source)
+        .output()
+        .expect("failed to start the compiler");
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "42\n  -6\n");
+}
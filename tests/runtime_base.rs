@@ -0,0 +1,34 @@
+// A program built with --runtime-base-toggle prints in hexadecimal when run with VIPER_BASE=16.
+// Running it needs nasm and a linker, so the test is ignored by default:
+// `cargo test -- --ignored` runs it.
+
+use std::path::Path;
+use std::process::Command;
+
+fn run_with_base(base: Option<&str>) -> String {
+    let source = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/programs/print_width.vp");
+    let assembly = Path::new(env!("CARGO_TARGET_TMPDIR")).join("runtime_base.asm");
+    let mut command = Command::new(env!("CARGO_BIN_EXE_Viper"));
+    command
+        .arg("--runtime-base-toggle")
+        .arg("--run")
+        .arg("--force")
+        .arg("-o")
+        .arg(&assembly)
+        .arg(source)
+        .env_remove("VIPER_BASE");
+    if let Some(base) = base {
+        command.env("VIPER_BASE", base);
+    }
+    let output = command.output().expect("failed to start the compiler");
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+#[ignore = "needs nasm and a linker"]
+fn base_from_environment() {
+    assert_eq!(run_with_base(None), "   42\n123456\n7\n   42000\n");
+    assert_eq!(run_with_base(Some("10")), "   42\n123456\n7\n   42000\n");
+    assert_eq!(run_with_base(Some("16")), "   2a\n1e240\n7\n    a410\n");
+}
@@ -0,0 +1,141 @@
+// Checks on the tokens the lexer produces, as --dump-tokens prints them (one per line, as
+// `line:column<TAB>start..end<TAB>token`) and as --emit=tokens-json does.
+
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+fn dump_tokens(inputs: &[&str], args: &[&str]) -> String {
+    let programs = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/programs");
+    let output = Command::new(env!("CARGO_BIN_EXE_Viper"))
+        .arg("--dump-tokens")
+        .args(args)
+        .args(inputs.iter().map(|input| programs.join(input)))
+        .output()
+        .expect("failed to start the compiler");
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    String::from_utf8(output.stdout).unwrap()
+}
+
+// Dump the tokens of `source`, written to a file called `name`
+fn dump_source(name: &str, source: &str, args: &[&str]) -> String {
+    let input = Path::new(env!("CARGO_TARGET_TMPDIR")).join(name);
+    fs::write(&input, source).unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_Viper"))
+        .arg("--dump-tokens")
+        .args(args)
+        .arg(&input)
+        .output()
+        .expect("failed to start the compiler");
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn spans_of_assignment() {
+    assert_eq!(
+        dump_source("spans.vp", "a = 1", &[]),
+        "1:1\t0..1\tIdentifier(\"a\")\n1:3\t2..3\tAssign\n1:5\t4..5\tNumber(1)\n1:6\t5..5\tEOF\n"
+    );
+}
+
+// The compiler reuses one lexer for every input, so dumping the tokens of several files must give
+// each the stream it gets on its own, with spans starting again at 1:1.
+#[test]
+fn reused_lexer_streams_are_independent() {
+    // The longer file goes first, so leftovers of it would show up in the second stream
+    let (first, second) = ("arithmetic.vp", "print_width.vp");
+    let both = dump_tokens(&[first, second], &[]);
+    let sections: Vec<&str> = both.split("# ").skip(1).collect();
+    assert_eq!(sections.len(), 2, "{}", both);
+
+    for (section, input) in sections.iter().zip([first, second]) {
+        let (header, tokens) = section.split_once('\n').unwrap();
+        assert!(header.ends_with(input), "{}", header);
+        assert_eq!(tokens, dump_tokens(&[input], &[]));
+    }
+}
+
+// The "value" strings of --emit=tokens-json output, unescaped
+fn json_values(json: &str) -> Vec<String> {
+    let mut values = Vec::new();
+    for rest in json.split("\"value\": \"").skip(1) {
+        let mut value = String::new();
+        let mut chars = rest.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '"' => break,
+                '\\' => match chars.next().unwrap() {
+                    'n' => value.push('\n'),
+                    't' => value.push('\t'),
+                    'u' => {
+                        let code: String = chars.by_ref().take(4).collect();
+                        let code = u32::from_str_radix(&code, 16).unwrap();
+                        value.push(char::from_u32(code).unwrap());
+                    }
+                    escaped => value.push(escaped),
+                },
+                c => value.push(c),
+            }
+        }
+        values.push(value);
+    }
+    values
+}
+
+// With --trivia the lexemes of all the tokens put together are the source itself, comments and
+// whitespace included
+#[test]
+fn trivia_tokens_reproduce_the_source() {
+    let programs = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/programs");
+    for input in ["block_comments.vp", "arithmetic.vp", "print_mixed.vp"] {
+        let source = fs::read_to_string(programs.join(input)).unwrap();
+        let output = Command::new(env!("CARGO_BIN_EXE_Viper"))
+            .args(["--emit=tokens-json", "--trivia"])
+            .arg(programs.join(input))
+            .output()
+            .expect("failed to start the compiler");
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        let json = String::from_utf8(output.stdout).unwrap();
+        if input == "block_comments.vp" {
+            assert!(json.contains("\"kind\": \"Comment\""), "{}", json);
+        }
+        assert_eq!(json_values(&json).concat(), source, "{}", input);
+    }
+}
+
+// A tab advances to the next multiple of the tab width: column 2 with the default of 1, 5 with 4
+#[test]
+fn tab_width_moves_columns() {
+    let first_token = |args: &[&str]| {
+        let tokens = dump_source("tab_width.vp", "\tx = 1\n", args);
+        tokens.lines().next().unwrap().to_string()
+    };
+    assert_eq!(first_token(&[]), "1:2\t1..2\tIdentifier(\"x\")");
+    assert_eq!(first_token(&["--tab-width", "4"]), "1:5\t1..2\tIdentifier(\"x\")");
+}
+
+// The tokens of `IF myVar PRINT`, written to a file called `name`, without their positions
+fn keyword_case_tokens(name: &str, args: &[&str]) -> Vec<String> {
+    let tokens = dump_source(name, "IF myVar PRINT\n", args);
+    tokens.lines().map(|line| line.rsplit('\t').next().unwrap().to_string()).take(3).collect()
+}
+
+#[test]
+fn uppercase_keyword_with_case_insensitive_keywords() {
+    let tokens = keyword_case_tokens("keyword_upper.vp", &["--case-insensitive-keywords"]);
+    assert_eq!(tokens[0], "If");
+    assert_eq!(tokens[2], "Print");
+}
+
+#[test]
+fn case_insensitive_keywords_keep_identifier_case() {
+    let tokens = keyword_case_tokens("keyword_identifier.vp", &["--case-insensitive-keywords"]);
+    assert_eq!(tokens[1], "Identifier(\"myVar\")");
+}
+
+#[test]
+fn uppercase_keyword_is_identifier_by_default() {
+    let tokens = keyword_case_tokens("keyword_default.vp", &[]);
+    assert_eq!(tokens, ["Identifier(\"IF\")", "Identifier(\"myVar\")", "Identifier(\"PRINT\")"]);
+}